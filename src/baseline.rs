@@ -0,0 +1,224 @@
+//! A record of known diagnostics so only new ones fail a run.
+//!
+//! Diagnostics are keyed by rule, file and a fingerprint of the code they point at that
+//! ignores where in the file that code is, so editing unrelated code above a known
+//! diagnostic doesn't make it new again.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    error::{Diagnostic, SourceMap},
+    EzError,
+};
+
+const VERSION: u64 = 1;
+
+/// A fingerprint of `diag` that survives the code it points at moving.
+///
+/// It covers the rule, the message and the whitespace normalized text of the lines the
+/// primary label is on.
+pub fn fingerprint(diag: &Diagnostic, source_map: &SourceMap) -> String {
+    let mut hash = Fnv::default();
+    hash.write(diag.code.as_bytes());
+    hash.write(diag.msg.as_bytes());
+
+    let primary = diag.primary();
+    let source = primary.and_then(|l| Some((l, source_map.get(&l.file)?)));
+    if let Some((label, source)) = source {
+        let start = source.lines.position(label.span.start()).line;
+        let end = source.lines.position(label.span.end()).line;
+        for line in start..=end {
+            for word in source.line(line).split_whitespace() {
+                hash.write(word.as_bytes());
+            }
+        }
+    }
+    format!("{:016x}", hash.0)
+}
+
+/// FNV-1a, the fingerprint has to be the same on every platform and every run.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self { Self(0xcbf2_9ce4_8422_2325) }
+}
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        // Separate each write so `ab`, `c` and `a`, `bc` differ.
+        self.0 ^= 0xff;
+        self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+}
+
+/// Rule, file relative to the root and fingerprint.
+type Key = (String, String, String);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    /// How many diagnostics share this key.
+    count: usize,
+    /// The message of the first one, only to make the file readable.
+    msg: String,
+}
+
+/// Known diagnostics, files are stored relative to `root` so a baseline can be shared.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Baseline {
+    root: PathBuf,
+    entries: BTreeMap<Key, Entry>,
+}
+
+impl Baseline {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_owned(), entries: BTreeMap::new() }
+    }
+
+    /// Read a baseline written by `save`, files in it are relative to `root`.
+    pub fn load(path: &Path, root: &Path) -> Result<Self, EzError> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            format!("Failed to read baseline `{}`: {}", path.display(), e)
+        })?;
+        let json: Value = serde_json::from_str(&text)?;
+        if json["version"].as_u64() != Some(VERSION) {
+            return Err(
+                format!("Unsupported baseline version in `{}`", path.display()).into()
+            );
+        }
+
+        let mut baseline = Self::new(root);
+        for entry in json["diagnostics"].as_array().into_iter().flatten() {
+            let field = |name: &str| {
+                entry[name].as_str().map(str::to_owned).ok_or(format!(
+                    "Baseline entry without a `{}` in `{}`",
+                    name,
+                    path.display()
+                ))
+            };
+            baseline.entries.insert(
+                (field("rule")?, field("file")?, field("fingerprint")?),
+                Entry {
+                    count: entry["count"].as_u64().unwrap_or(1) as usize,
+                    msg: field("message").unwrap_or_default(),
+                },
+            );
+        }
+        Ok(baseline)
+    }
+
+    /// A baseline of every diagnostic in `diags`.
+    pub fn from_diagnostics(
+        root: &Path,
+        diags: &[Diagnostic],
+        source_map: &SourceMap,
+    ) -> Self {
+        let mut baseline = Self::new(root);
+        for diag in diags {
+            baseline
+                .entries
+                .entry(baseline_key(&baseline.root, diag, source_map))
+                .or_insert_with(|| Entry { count: 0, msg: diag.msg.clone() })
+                .count += 1;
+        }
+        baseline
+    }
+
+    /// The same baseline for a copy of the workspace at `root`.
+    pub fn with_root(mut self, root: &Path) -> Self {
+        self.root = root.to_owned();
+        self
+    }
+
+    /// Is `diag` known, each recorded diagnostic only matches once.
+    pub fn take(&mut self, diag: &Diagnostic, source_map: &SourceMap) -> bool {
+        match self.entries.get_mut(&baseline_key(&self.root, diag, source_map)) {
+            Some(entry) if entry.count > 0 => {
+                entry.count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let diagnostics = self
+            .entries
+            .iter()
+            .map(|((rule, file, fingerprint), entry)| {
+                json!({
+                    "rule": rule,
+                    "file": file,
+                    "fingerprint": fingerprint,
+                    "count": entry.count,
+                    "message": entry.msg,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "version": VERSION, "diagnostics": diagnostics })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), EzError> {
+        let text = serde_json::to_string_pretty(&self.to_json())? + "\n";
+        fs::write(path, text).map_err(|e| {
+            format!("Failed to write baseline `{}`: {}", path.display(), e).into()
+        })
+    }
+}
+
+fn baseline_key(root: &Path, diag: &Diagnostic, source_map: &SourceMap) -> Key {
+    let file = diag.primary().map_or(diag.file.as_str(), |l| l.file.as_str());
+    let file = Path::new(file).strip_prefix(root).unwrap_or_else(|_| Path::new(file));
+    (
+        diag.code.clone(),
+        // The same baseline works on every platform.
+        file.to_string_lossy().replace('\\', "/"),
+        fingerprint(diag, source_map),
+    )
+}
+
+#[test]
+fn baseline_ignores_moved_code() {
+    use syntax::TextRange;
+
+    use crate::error::{Label, Severity};
+
+    let root = Path::new("/ws");
+    let file = "/ws/ruma/src/lib.rs";
+    let diag = |text: &str, map: &mut SourceMap| {
+        map.add_file(file, || text.to_owned());
+        let start = text.find("foo!").unwrap() as u32;
+        Diagnostic::new(Severity::Error, "RC0002", "bad macro", file).with_label(
+            Label::primary(file, TextRange::new(start.into(), (start + 4).into()), ""),
+        )
+    };
+
+    let mut old = SourceMap::default();
+    let known = diag("fn a() {\n    foo!(a);\n}\n", &mut old);
+    let mut baseline = Baseline::from_diagnostics(root, &[known.clone(), known], &old);
+    assert_eq!(baseline.to_json()["diagnostics"][0]["file"], "ruma/src/lib.rs");
+    assert_eq!(baseline.to_json()["diagnostics"][0]["count"], 2);
+
+    // Moved down and indented differently, still the same diagnostic.
+    let mut new = SourceMap::default();
+    let moved = diag("use b;\n\nfn a() {\n  foo!(a);\n}\n", &mut new);
+    assert!(baseline.take(&moved, &new));
+    assert!(baseline.take(&moved, &new));
+    assert!(!baseline.take(&moved, &new));
+
+    let mut changed = SourceMap::default();
+    let changed_diag = diag("fn a() {\n    foo!(b);\n}\n", &mut changed);
+    let mut baseline =
+        Baseline::from_diagnostics(root, &[changed_diag.clone()], &changed);
+    assert!(!baseline.take(&moved, &new));
+    assert!(baseline.take(&changed_diag, &changed));
+}
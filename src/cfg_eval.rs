@@ -0,0 +1,303 @@
+//! Evaluating `#[cfg]` and `#[cfg_attr]` so rules only see code that is compiled.
+//!
+//! Only what is known is evaluated, code is skipped when its predicate is certainly
+//! false. A cfg nothing set, like `test`, or `feature` when no feature flags were given,
+//! keeps the code so rules still see it.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use syntax::{
+    ast::{self, AstNode},
+    SyntaxNode,
+};
+
+/// The cfg options a crate is checked with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CfgOptions {
+    /// Enabled atoms like `unix`.
+    atoms: BTreeSet<String>,
+    /// Atoms that are known to be off unless they are in `atoms`.
+    known_atoms: BTreeSet<String>,
+    /// Every value of a key like `feature`, keys not in here are unknown.
+    values: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl CfgOptions {
+    /// The options of `target`, a triple like `x86_64-unknown-linux-gnu`.
+    pub fn for_target(target: &str) -> Self {
+        let arch = target.split('-').next().unwrap_or_default();
+        let arch = match arch {
+            "i386" | "i586" | "i686" => "x86",
+            arch if arch.starts_with("arm") || arch.starts_with("thumb") => "arm",
+            arch => arch,
+        };
+        let os = [
+            "android",
+            "linux",
+            "windows",
+            "macos",
+            "ios",
+            "freebsd",
+            "netbsd",
+            "openbsd",
+            "dragonfly",
+            "none",
+        ]
+        .iter()
+        .find(|os| target.contains(*os))
+        .copied()
+        .unwrap_or(if target.contains("darwin") { "macos" } else { "unknown" });
+        let family = match os {
+            "windows" => Some("windows"),
+            _ if arch.starts_with("wasm") => Some("wasm"),
+            "none" | "unknown" => None,
+            _ => Some("unix"),
+        };
+
+        let mut cfg = Self::default();
+        cfg.known_atoms.extend(vec!["unix".to_owned(), "windows".to_owned()]);
+        // `wasm` is only a family, not an atom.
+        if let Some(family) = family.filter(|f| *f != "wasm") {
+            cfg.atoms.insert(family.to_owned());
+        }
+        cfg.set("target_arch", arch);
+        cfg.set("target_os", os);
+        cfg.set("target_pointer_width", if arch.contains("64") { "64" } else { "32" });
+        cfg.values
+            .entry("target_family".to_owned())
+            .or_default()
+            .extend(family.map(str::to_owned));
+        cfg
+    }
+
+    /// Parse cfgs written like `unix` or `feature="std"`, as in a `rust-project.json`.
+    /// Every key given becomes known.
+    pub fn from_list(cfgs: &[String]) -> Self {
+        let mut cfg = Self::default();
+        for item in cfgs {
+            match item.find('=') {
+                Some(eq) => {
+                    cfg.set(item[..eq].trim(), item[eq + 1..].trim().trim_matches('"'))
+                }
+                None => {
+                    cfg.atoms.insert(item.trim().to_owned());
+                }
+            }
+        }
+        cfg
+    }
+
+    /// Enable exactly `features`, every other feature is off.
+    pub fn with_features(mut self, features: impl IntoIterator<Item = String>) -> Self {
+        self.values.insert("feature".to_owned(), features.into_iter().collect());
+        self
+    }
+
+    /// Add everything `other` knows.
+    pub fn merge(&mut self, other: &CfgOptions) {
+        self.atoms.extend(other.atoms.iter().cloned());
+        self.known_atoms.extend(other.known_atoms.iter().cloned());
+        for (key, values) in &other.values {
+            self.values.entry(key.clone()).or_default().extend(values.iter().cloned());
+        }
+    }
+
+    /// The enabled options as rust-analyzer's crate graph takes them.
+    pub fn to_ra(&self) -> cfg::CfgOptions {
+        let mut opts = cfg::CfgOptions::default();
+        for atom in &self.atoms {
+            opts.insert_atom(atom.into());
+        }
+        for (key, values) in &self.values {
+            for value in values {
+                opts.insert_key_value(key.into(), value.into());
+            }
+        }
+        opts
+    }
+
+    /// Is `node` compiled, false only when a `cfg` on it is certainly false.
+    pub fn is_enabled(&self, node: &SyntaxNode) -> bool {
+        node.children()
+            .filter_map(ast::Attr::cast)
+            .all(|attr| self.attr(&attr) != Some(false))
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        self.values.entry(key.to_owned()).or_default().insert(value.to_owned());
+    }
+
+    /// Evaluate a `cfg` or the `cfg`s a `cfg_attr` expands to, `None` if unknown or the
+    /// attribute isn't either.
+    fn attr(&self, attr: &ast::Attr) -> Option<bool> {
+        let name = attr.path()?.syntax().text().to_string();
+        let tokens = attr
+            .token_tree()?
+            .syntax()
+            .descendants_with_tokens()
+            .filter_map(|it| it.into_token())
+            .filter(|token| !token.kind().is_trivia())
+            .map(|token| token.text().to_string())
+            .collect::<Vec<_>>();
+        let mut tokens = tokens.as_slice();
+        match name.as_str() {
+            "cfg" => self.eval(&parse_list(&mut tokens)?.pop()?),
+            "cfg_attr" => self.cfg_attr(tokens),
+            _ => None,
+        }
+    }
+
+    /// `(predicate, attr, ...)`, only a `cfg` among the attrs can turn the item off.
+    fn cfg_attr(&self, tokens: &[String]) -> Option<bool> {
+        let mut tokens = tokens;
+        if !eat(&mut tokens, "(") {
+            return None;
+        }
+        let predicate = parse(&mut tokens)?;
+        let mut enabled = Some(true);
+        while eat(&mut tokens, ",") {
+            if eat(&mut tokens, "cfg") {
+                enabled = and(enabled, self.eval(&parse_list(&mut tokens)?.pop()?));
+            } else {
+                // Some other attribute, skip to the next top level comma.
+                let mut depth = 0;
+                while let Some(token) = tokens.first() {
+                    match token.as_str() {
+                        "(" | "[" | "{" => depth += 1,
+                        ")" | "]" | "}" if depth == 0 => break,
+                        ")" | "]" | "}" => depth -= 1,
+                        "," if depth == 0 => break,
+                        _ => {}
+                    }
+                    tokens = &tokens[1..];
+                }
+            }
+        }
+        match self.eval(&predicate) {
+            Some(true) => enabled,
+            _ => None,
+        }
+    }
+
+    fn eval(&self, cfg: &Cfg) -> Option<bool> {
+        match cfg {
+            Cfg::Atom(atom) if self.atoms.contains(atom) => Some(true),
+            Cfg::Atom(atom) if self.known_atoms.contains(atom) => Some(false),
+            Cfg::Atom(_) => None,
+            Cfg::KeyValue(key, value) => self.values.get(key).map(|v| v.contains(value)),
+            Cfg::All(cfgs) => {
+                cfgs.iter().fold(Some(true), |acc, c| and(acc, self.eval(c)))
+            }
+            Cfg::Any(cfgs) => {
+                cfgs.iter().fold(Some(false), |acc, c| or(acc, self.eval(c)))
+            }
+            Cfg::Not(cfg) => self.eval(cfg).map(|enabled| !enabled),
+        }
+    }
+}
+
+fn and(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+fn or(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+/// A `cfg` predicate.
+#[derive(Clone, Debug, PartialEq)]
+enum Cfg {
+    Atom(String),
+    KeyValue(String, String),
+    All(Vec<Cfg>),
+    Any(Vec<Cfg>),
+    Not(Box<Cfg>),
+}
+
+/// Parse one predicate from the start of `tokens`.
+fn parse(tokens: &mut &[String]) -> Option<Cfg> {
+    let (name, rest) = tokens.split_first()?;
+    *tokens = rest;
+    match tokens.first().map(String::as_str) {
+        Some("=") => {
+            let value = tokens.get(1)?.trim_matches('"').to_owned();
+            *tokens = &tokens[2..];
+            Some(Cfg::KeyValue(name.clone(), value))
+        }
+        Some("(") => {
+            let mut list = parse_list(tokens)?;
+            match name.as_str() {
+                "all" => Some(Cfg::All(list)),
+                "any" => Some(Cfg::Any(list)),
+                "not" if list.len() == 1 => Some(Cfg::Not(Box::new(list.pop()?))),
+                _ => None,
+            }
+        }
+        _ => Some(Cfg::Atom(name.clone())),
+    }
+}
+
+/// Parse `(predicate, ...)` from the start of `tokens`.
+fn parse_list(tokens: &mut &[String]) -> Option<Vec<Cfg>> {
+    if !eat(tokens, "(") {
+        return None;
+    }
+    let mut list = vec![];
+    loop {
+        match tokens.first()?.as_str() {
+            ")" => {
+                *tokens = &tokens[1..];
+                return Some(list);
+            }
+            "," => *tokens = &tokens[1..],
+            _ => list.push(parse(tokens)?),
+        }
+    }
+}
+
+/// Skip the first token if it is `text`.
+fn eat(tokens: &mut &[String], text: &str) -> bool {
+    match tokens.split_first() {
+        Some((first, rest)) if first == text => {
+            *tokens = rest;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn evaluate_cfgs() {
+    let cfg = CfgOptions::for_target("x86_64-unknown-linux-gnu")
+        .with_features(vec!["client".to_owned()]);
+    let eval = |text: &str| {
+        let tokens = text.split_whitespace().map(str::to_owned).collect::<Vec<_>>();
+        cfg.eval(&parse_list(&mut tokens.as_slice()).unwrap().pop().unwrap())
+    };
+
+    assert_eq!(eval("( unix )"), Some(true));
+    assert_eq!(eval("( windows )"), Some(false));
+    assert_eq!(eval("( test )"), None);
+    assert_eq!(eval("( feature = \"client\" )"), Some(true));
+    assert_eq!(
+        eval("( all ( target_os = \"linux\" , feature = \"server\" ) )"),
+        Some(false)
+    );
+    assert_eq!(eval("( any ( test , feature = \"client\" ) )"), Some(true));
+    assert_eq!(eval("( not ( any ( test , windows ) ) )"), None);
+
+    let tokens = "( unix , derive ( Debug ) , cfg ( feature = \"server\" ) )"
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    assert_eq!(cfg.cfg_attr(&tokens), Some(false));
+    assert_eq!(CfgOptions::default().cfg_attr(&tokens), None);
+}
@@ -0,0 +1,366 @@
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    error::{ColorChoice, MessageFormat},
+    macro_exp::ExpansionLimits,
+    project::Features,
+};
+
+/// The sub-command to run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Lint the workspace in the current directory.
+    Check,
+    /// Expand every macro at two revisions and diff the output.
+    ExpandDiff { old: String, new: String },
+    /// Lint two revisions and report the diagnostics introduced and fixed between them.
+    Diff { base: String, head: String },
+    /// Keep workspaces loaded and answer check requests over a Unix socket.
+    Server,
+    /// Run as a language server over stdio.
+    Lsp,
+    /// Install a git pre-commit hook checking staged files.
+    InstallHook,
+}
+
+impl Default for Command {
+    fn default() -> Self { Self::Check }
+}
+
+/// A report written to a file alongside the normal output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Report {
+    /// `--report html=<path>`
+    Html(PathBuf),
+    /// `--report <message-format>=<path>`, written as diagnostics are found.
+    Format(MessageFormat, PathBuf),
+}
+
+/// The parsed command line arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    /// Only these packages are checked, all workspace members when empty.
+    pub packages: Vec<String>,
+    /// Print how long each phase, rule and file took.
+    pub timings: bool,
+    /// Expand every macro call of the checked files, reporting expansions that exceed
+    /// the `limits`.
+    pub expand: bool,
+    pub limits: ExpansionLimits,
+    /// Forward this run to a running `ruma-check server`.
+    pub client: bool,
+    /// The socket the server listens on, see `server::default_socket` for the default.
+    pub socket: Option<PathBuf>,
+    pub color: ColorChoice,
+    pub message_format: MessageFormat,
+    /// Order diagnostics by rule before file and position.
+    pub group_by_rule: bool,
+    pub reports: Vec<Report>,
+    /// Suppress the diagnostics recorded in this baseline.
+    pub baseline: Option<PathBuf>,
+    /// Record every diagnostic of this run in a new baseline.
+    pub write_baseline: Option<PathBuf>,
+    /// Only check files changed since this git revision.
+    pub changed_since: Option<String>,
+    /// Only check files staged in git, as they are staged.
+    pub staged: bool,
+    /// Don't let cargo use the network, fall back to reading `Cargo.toml` files.
+    pub offline: bool,
+    /// `--offline` and fail when `Cargo.lock` is out of date, like cargo's flag.
+    pub frozen: bool,
+    /// Also check packages outside the workspace that members depend on by path.
+    pub path_deps: bool,
+    pub features: Features,
+    /// Check once for each feature set of `Package::feature_matrix`.
+    pub feature_matrix: bool,
+    /// The target triple `#[cfg]`s are evaluated for, unknown target cfgs keep the code.
+    pub target: Option<String>,
+}
+
+impl Args {
+    /// Parse the arguments, not including the binary name.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut positional = vec![];
+
+        // `--flag=value` is the same as `--flag value`.
+        let args = args
+            .iter()
+            .flat_map(|arg| match arg.find('=') {
+                Some(eq) if arg.starts_with("--") => {
+                    vec![arg[..eq].to_owned(), arg[eq + 1..].to_owned()]
+                }
+                _ => vec![arg.clone()],
+            })
+            .collect::<Vec<_>>();
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-p" | "--package" => {
+                    parsed.packages.push(value(arg, &mut iter)?.clone())
+                }
+                "--timings" => parsed.timings = true,
+                "--group-by-rule" => parsed.group_by_rule = true,
+                "--client" => parsed.client = true,
+                "--socket" => parsed.socket = Some(value(arg, &mut iter)?.into()),
+                "--color" => {
+                    parsed.color = match value(arg, &mut iter)?.as_str() {
+                        "auto" => ColorChoice::Auto,
+                        "always" => ColorChoice::Always,
+                        "never" => ColorChoice::Never,
+                        other => {
+                            return Err(format!(
+                                "`--color` expects `auto`, `always` or `never`, found `{}`",
+                                other
+                            ));
+                        }
+                    }
+                }
+                "--message-format" => {
+                    let format = value(arg, &mut iter)?;
+                    parsed.message_format = message_format(format).ok_or(format!(
+                        "`--message-format` expects `human`, `json`, `sarif`, `github`, \
+                         `checkstyle` or `junit`, found `{}`",
+                        format
+                    ))?;
+                }
+                "--report" => {
+                    let report = value(arg, &mut iter)?;
+                    let (kind, path) = match report.find('=') {
+                        Some(eq) => (&report[..eq], &report[eq + 1..]),
+                        None => (report.as_str(), ""),
+                    };
+                    parsed.reports.push(match (kind, message_format(kind)) {
+                        _ if path.is_empty() => {
+                            return Err(format!(
+                                "`--report` requires a path, `{}=<path>`",
+                                kind
+                            ));
+                        }
+                        ("html", _) => Report::Html(path.into()),
+                        (_, Some(format)) => Report::Format(format, path.into()),
+                        (_, None) => {
+                            return Err(format!(
+                                "`--report` expects `html` or a message format, found `{}`",
+                                kind
+                            ));
+                        }
+                    })
+                }
+                "--baseline" => parsed.baseline = Some(value(arg, &mut iter)?.into()),
+                "--write-baseline" => {
+                    parsed.write_baseline = Some(value(arg, &mut iter)?.into())
+                }
+                "--changed-since" => {
+                    parsed.changed_since = Some(value(arg, &mut iter)?.clone())
+                }
+                "--staged" => parsed.staged = true,
+                "--offline" => parsed.offline = true,
+                "--frozen" => parsed.frozen = true,
+                "--path-deps" => parsed.path_deps = true,
+                "-F" | "--features" => parsed.features.features.extend(
+                    value(arg, &mut iter)?
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|f| !f.is_empty())
+                        .map(str::to_owned),
+                ),
+                "--all-features" => parsed.features.all = true,
+                "--no-default-features" => parsed.features.no_default = true,
+                "--feature-matrix" => parsed.feature_matrix = true,
+                "--target" => parsed.target = Some(value(arg, &mut iter)?.clone()),
+                "--expand" => parsed.expand = true,
+                "--expand-timeout" => parsed.limits.timeout = seconds(arg, &mut iter)?,
+                "--expand-budget" => parsed.limits.budget = seconds(arg, &mut iter)?,
+                "--expand-max-bytes" => {
+                    parsed.limits.max_output = value(arg, &mut iter)?
+                        .parse()
+                        .map_err(|_| format!("`{}` expects a number of bytes", arg))?;
+                }
+                flag if flag.starts_with('-') => {
+                    return Err(format!("Unknown flag `{}`", flag));
+                }
+                _ => positional.push(arg.as_str()),
+            }
+        }
+
+        parsed.command = match positional.as_slice() {
+            [] => Command::Check,
+            ["server"] => Command::Server,
+            ["lsp"] => Command::Lsp,
+            ["install-hook"] => Command::InstallHook,
+            ["expand-diff", old, new] => {
+                Command::ExpandDiff { old: old.to_string(), new: new.to_string() }
+            }
+            ["expand-diff", ..] => {
+                return Err("usage: ruma-check expand-diff <rev-a> <rev-b>".to_owned());
+            }
+            ["diff", base, head] => {
+                Command::Diff { base: base.to_string(), head: head.to_string() }
+            }
+            ["diff", ..] => return Err("usage: ruma-check diff <base> <head>".to_owned()),
+            [cmd, ..] => return Err(format!("Unknown command `{}`", cmd)),
+        };
+
+        if parsed.staged && parsed.changed_since.is_some() {
+            return Err(
+                "`--staged` and `--changed-since` can't be used together".to_owned()
+            );
+        }
+        // A baseline of part of the workspace would forget everything else.
+        if parsed.write_baseline.is_some()
+            && (parsed.staged || parsed.changed_since.is_some())
+        {
+            return Err(
+                "`--write-baseline` needs the whole workspace, it can't be used with \
+                 `--staged` or `--changed-since`"
+                    .to_owned(),
+            );
+        }
+
+        Ok(parsed)
+    }
+
+    /// Should the package named `name` be checked.
+    pub fn selected(&self, name: &str) -> bool {
+        self.packages.is_empty() || self.packages.iter().any(|p| p == name)
+    }
+}
+
+fn message_format(name: &str) -> Option<MessageFormat> {
+    Some(match name {
+        "human" => MessageFormat::Human,
+        "json" => MessageFormat::Json,
+        "sarif" => MessageFormat::Sarif,
+        "github" => MessageFormat::Github,
+        "checkstyle" => MessageFormat::Checkstyle,
+        "junit" => MessageFormat::Junit,
+        _ => return None,
+    })
+}
+
+fn value<'a>(
+    flag: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+) -> Result<&'a String, String> {
+    iter.next().ok_or(format!("`{}` requires a value", flag))
+}
+
+fn seconds<'a>(
+    flag: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+) -> Result<Duration, String> {
+    value(flag, iter)?
+        .parse()
+        .ok()
+        .filter(|secs: &f64| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or(format!("`{}` expects a number of seconds", flag))
+}
+
+#[test]
+fn parse_expand_diff() {
+    let args = ["expand-diff", "main", "HEAD", "-p", "ruma-common"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        Args::parse(&args).unwrap(),
+        Args {
+            command: Command::ExpandDiff { old: "main".into(), new: "HEAD".into() },
+            packages: vec!["ruma-common".into()],
+            ..Args::default()
+        }
+    );
+    assert!(Args::parse(&["expand-diff".to_owned()]).is_err());
+}
+
+#[test]
+fn parse_expand() {
+    let args = Args::parse(&["--expand".to_owned(), "--expand-budget=5".to_owned()]);
+    let args = args.unwrap();
+    assert!(args.expand);
+    assert_eq!(args.limits.budget, Duration::from_secs(5));
+}
+
+#[test]
+fn parse_diff() {
+    assert_eq!(
+        Args::parse(&["diff".to_owned(), "main".to_owned(), "HEAD".to_owned()])
+            .unwrap()
+            .command,
+        Command::Diff { base: "main".into(), head: "HEAD".into() }
+    );
+}
+
+#[test]
+fn parse_color() {
+    let args = Args::parse(&["--color=never".to_owned()]).unwrap();
+    assert_eq!(args.color, ColorChoice::Never);
+}
+
+#[test]
+fn parse_message_format() {
+    let args = Args::parse(&["--message-format=json".to_owned()]).unwrap();
+    assert_eq!(args.message_format, MessageFormat::Json);
+}
+
+#[test]
+fn parse_report() {
+    let args = Args::parse(&["--report=html=out/report.html".to_owned()]).unwrap();
+    assert_eq!(args.reports, vec![Report::Html("out/report.html".into())]);
+    assert!(Args::parse(&["--report".to_owned(), "pdf=a".to_owned()]).is_err());
+
+    let args = Args::parse(&["--report=sarif=ruma.sarif".to_owned()]).unwrap();
+    assert_eq!(
+        args.reports,
+        vec![Report::Format(MessageFormat::Sarif, "ruma.sarif".into())]
+    );
+}
+
+#[test]
+fn parse_features() {
+    let args = Args::parse(&[
+        "--features".to_owned(),
+        "client, ruma-events/markdown".to_owned(),
+        "--no-default-features".to_owned(),
+    ])
+    .unwrap();
+    assert_eq!(
+        args.features,
+        Features {
+            features: vec!["client".into(), "ruma-events/markdown".into()],
+            no_default: true,
+            ..Features::default()
+        }
+    );
+}
+
+#[test]
+fn parse_changes() {
+    let args = Args::parse(&["--changed-since=main".to_owned()]).unwrap();
+    assert_eq!(args.changed_since, Some("main".to_owned()));
+    assert!(
+        Args::parse(&["--staged".to_owned(), "--changed-since=main".to_owned()]).is_err()
+    );
+    assert_eq!(
+        Args::parse(&["install-hook".to_owned()]).unwrap().command,
+        Command::InstallHook
+    );
+}
+
+#[test]
+fn parse_path_deps() {
+    assert!(
+        Args::parse(&["--path-deps".to_owned(), "--offline".to_owned()])
+            .unwrap()
+            .path_deps
+    );
+}
+
+#[test]
+fn parse_frozen() {
+    assert!(Args::parse(&["--frozen".to_owned()]).unwrap().frozen);
+}
@@ -0,0 +1,203 @@
+//! Which diagnostics a change introduces and which it fixes.
+
+use std::{
+    collections::BTreeMap,
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    baseline::Baseline,
+    check_workspace,
+    cli::Args,
+    error::{CargoTarget, Diagnostic, Emitter},
+    git::{self, Worktree},
+    project::CargoInfo,
+    EzError,
+};
+
+/// The diagnostics of one revision of the workspace.
+struct Checked {
+    /// The workspace inside the worktree the revision was checked out in.
+    root: PathBuf,
+    emitter: Emitter,
+}
+
+/// Check the selected packages at `base` and `head`, push the diagnostics `head`
+/// introduces to the `emitter` and print the ones it fixes and a summary per rule to
+/// stderr.
+///
+/// Diagnostics are matched by fingerprint, so code that only moved is not reported.
+/// Introduced diagnostics name the files in `dir`, not in the worktree of `head`.
+/// Returns the number of introduced diagnostics.
+pub fn diag_diff(
+    dir: &Path,
+    base: &str,
+    head: &str,
+    args: &Args,
+    emitter: &mut Emitter,
+) -> Result<usize, EzError> {
+    let repo = git::repo_root(dir)?;
+    // Check the same workspace in each worktree as the one we were run in.
+    let checked = dir.canonicalize()?;
+    let workspace = checked.strip_prefix(&repo).unwrap_or_else(|_| Path::new(""));
+
+    let before = {
+        let tree = Worktree::checkout(&repo, base)?;
+        check(tree.path().canonicalize()?.join(workspace), args)?
+    };
+    let after = {
+        let tree = Worktree::checkout(&repo, head)?;
+        check(tree.path().canonicalize()?.join(workspace), args)?
+    };
+
+    let introduced = unmatched(&after, &before);
+    let fixed = unmatched(&before, &after);
+
+    let mut by_rule = BTreeMap::<&str, (usize, usize)>::new();
+    for diag in &introduced {
+        let files = iter::once(&diag.file)
+            .chain(diag.labels.iter().map(|l| &l.file))
+            .chain(diag.suggestions.iter().flat_map(|s| &s.edits).map(|e| &e.file));
+        for file in files {
+            if let Some(source) = after.emitter.source_map().get(file) {
+                emitter.add_file(&moved(file, &after.root, &checked), &source.text);
+            }
+        }
+        emitter.push(relocate(diag, &after.root, &checked));
+        by_rule.entry(&diag.code).or_default().0 += 1;
+    }
+    emitter.flush()?;
+
+    for diag in &fixed {
+        eprintln!("fixed: {}", describe(diag, &before));
+        by_rule.entry(&diag.code).or_default().1 += 1;
+    }
+    for (code, (introduced, fixed)) in &by_rule {
+        eprintln!("{}: {} introduced, {} fixed", code, introduced, fixed);
+    }
+
+    eprintln!(
+        "{} diagnostics introduced and {} fixed between `{}` and `{}`",
+        introduced.len(),
+        fixed.len(),
+        base,
+        head
+    );
+    Ok(introduced.len())
+}
+
+fn check(root: PathBuf, args: &Args) -> Result<Checked, EzError> {
+    let info = CargoInfo::build_crate_root(&root, args)?;
+    let mut emitter = Emitter::default();
+    check_workspace(&info, args, None, None, None, &mut emitter)?;
+    Ok(Checked { root, emitter })
+}
+
+/// The reported diagnostics of `new` without a match in `old`, each diagnostic of `old`
+/// matches only one.
+fn unmatched<'a>(new: &'a Checked, old: &Checked) -> Vec<&'a Diagnostic> {
+    let known = old.emitter.reported().cloned().collect::<Vec<_>>();
+    let mut known =
+        Baseline::from_diagnostics(&old.root, &known, old.emitter.source_map())
+            .with_root(&new.root);
+    new.emitter
+        .reported()
+        .filter(|diag| !known.take(diag, new.emitter.source_map()))
+        .collect()
+}
+
+/// `file` in the worktree at `from` in the directory `to` instead.
+fn moved(file: &str, from: &Path, to: &Path) -> String {
+    match Path::new(file).strip_prefix(from) {
+        Ok(rel) => to.join(rel).display().to_string(),
+        Err(_) => file.to_owned(),
+    }
+}
+
+/// `diag` with every path into the worktree at `from` moved to `to`.
+fn relocate(diag: &Diagnostic, from: &Path, to: &Path) -> Diagnostic {
+    let mut diag = diag.clone();
+    diag.file = moved(&diag.file, from, to);
+    for label in &mut diag.labels {
+        label.file = moved(&label.file, from, to);
+    }
+    for edit in diag.suggestions.iter_mut().flat_map(|s| &mut s.edits) {
+        edit.file = moved(&edit.file, from, to);
+    }
+    if let Some(target) = &diag.target {
+        let package_id = target
+            .package_id
+            .replace(&from.display().to_string(), &to.display().to_string());
+        diag.target = Some(Arc::new(CargoTarget {
+            package_id,
+            manifest_path: moved(&target.manifest_path, from, to),
+            src_path: moved(&target.src_path, from, to),
+            ..CargoTarget::clone(target)
+        }));
+    }
+    diag
+}
+
+/// `RC0002 ruma/src/lib.rs:12: message` with the file relative to the workspace.
+fn describe(diag: &Diagnostic, checked: &Checked) -> String {
+    let (file, line) = match diag.primary() {
+        Some(l) => (
+            l.file.as_str(),
+            checked
+                .emitter
+                .source_map()
+                .position(&l.file, l.span.start())
+                .map(|p| p.line),
+        ),
+        None => (diag.file.as_str(), None),
+    };
+    let file =
+        Path::new(file).strip_prefix(&checked.root).unwrap_or_else(|_| Path::new(file));
+    match line {
+        Some(line) => format!("{} {}:{}: {}", diag.code, file.display(), line, diag.msg),
+        None => format!("{} {}: {}", diag.code, file.display(), diag.msg),
+    }
+}
+
+#[test]
+fn introduced_and_fixed() {
+    use syntax::TextRange;
+
+    use crate::error::{Label, Severity};
+
+    let checked = |root: &str, text: &str, codes: &[&str]| {
+        let file = format!("{}/ruma/src/lib.rs", root);
+        let mut emitter = Emitter::default();
+        emitter.add_file(&file, text);
+        let start = text.find("foo!").unwrap() as u32;
+        for code in codes {
+            emitter.push(
+                Diagnostic::new(Severity::Error, code, "bad", &file).with_label(
+                    Label::primary(
+                        &file,
+                        TextRange::new(start.into(), (start + 4).into()),
+                        "",
+                    ),
+                ),
+            );
+        }
+        Checked { root: root.into(), emitter }
+    };
+
+    let before = checked("/tmp/a", "fn a() {\n    foo!(a);\n}\n", &["RC0001", "RC0002"]);
+    // The call moved down a line, only `RC0003` is new.
+    let after = checked("/tmp/b", "\nfn a() {\n    foo!(a);\n}\n", &["RC0002", "RC0003"]);
+
+    let introduced = unmatched(&after, &before);
+    assert_eq!(introduced.len(), 1);
+    assert_eq!(describe(introduced[0], &after), "RC0003 ruma/src/lib.rs:3: bad");
+    let relocated = relocate(introduced[0], &after.root, Path::new("/ws"));
+    assert_eq!(relocated.file, "/ws/ruma/src/lib.rs");
+    assert_eq!(relocated.labels[0].file, "/ws/ruma/src/lib.rs");
+
+    let fixed = unmatched(&before, &after);
+    assert_eq!(fixed.len(), 1);
+    assert_eq!(fixed[0].code, "RC0001");
+}
@@ -0,0 +1,118 @@
+//! A small line based diff used to show how two pieces of text differ.
+
+/// The number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Edit<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Produce a unified diff of `old` and `new`, an empty string means no lines changed.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+    let edits = edits(&old_lines, &new_lines);
+
+    if edits.iter().all(|e| matches!(e, Edit::Same(_))) {
+        return String::new();
+    }
+
+    let mut buffer = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (start, end) in hunks(&edits) {
+        // Line numbers are 1 based, count what came before the hunk.
+        let (mut old_start, mut new_start) = (1, 1);
+        for edit in &edits[..start] {
+            match edit {
+                Edit::Same(_) => {
+                    old_start += 1;
+                    new_start += 1;
+                }
+                Edit::Removed(_) => old_start += 1,
+                Edit::Added(_) => new_start += 1,
+            }
+        }
+        let hunk = &edits[start..end];
+        let old_len = hunk.iter().filter(|e| !matches!(e, Edit::Added(_))).count();
+        let new_len = hunk.iter().filter(|e| !matches!(e, Edit::Removed(_))).count();
+
+        buffer.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_len, new_start, new_len
+        ));
+        for edit in hunk {
+            let (prefix, line) = match edit {
+                Edit::Same(l) => (' ', l),
+                Edit::Removed(l) => ('-', l),
+                Edit::Added(l) => ('+', l),
+            };
+            buffer.push(prefix);
+            buffer.push_str(line);
+            buffer.push('\n');
+        }
+    }
+
+    buffer
+}
+
+/// Walk the longest common subsequence table to build the list of edits.
+fn edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Edit<'a>> {
+    let mut lcs = vec![vec![0_usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut edits = vec![];
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            edits.push(Edit::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            edits.push(Edit::Removed(old[i]));
+            i += 1;
+        } else {
+            edits.push(Edit::Added(new[j]));
+            j += 1;
+        }
+    }
+    edits.extend(old[i..].iter().map(|l| Edit::Removed(l)));
+    edits.extend(new[j..].iter().map(|l| Edit::Added(l)));
+    edits
+}
+
+/// Group the changed edits, with their context, into `start..end` ranges.
+fn hunks(edits: &[Edit<'_>]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (idx, _) in edits.iter().enumerate().filter(|(_, e)| !matches!(e, Edit::Same(_)))
+    {
+        let start = idx.saturating_sub(CONTEXT);
+        let end = (idx + CONTEXT + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if last.1 >= start => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+#[test]
+fn unified_diff() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+    let new = "a\nb\nc\nD\ne\nf\ng\nh\n";
+
+    assert_eq!(
+        unified(old, new, "a/file", "b/file"),
+        "--- a/file\n+++ b/file\n@@ -1,7 +1,7 @@\n a\n b\n c\n-d\n+D\n e\n f\n g\n"
+    );
+    assert_eq!(unified(old, old, "a/file", "b/file"), "");
+}
@@ -1,103 +1,533 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    path::PathBuf,
+    sync::Arc,
+};
+
 use syntax::{SyntaxNode, TextRange};
 
-#[derive(Clone, Debug, Default)]
+use crate::{
+    baseline::Baseline,
+    rules::{Registry, RuleMeta},
+};
+
+mod github;
+mod html;
+mod json;
+mod render;
+mod sarif;
+mod sink;
+mod source_map;
+mod xml;
+
+pub use render::ColorChoice;
+use render::{Annotation, FooterKind, Snippet};
+pub use sink::{sink, Buffer, Sink};
+pub use source_map::{LineIndex, Position, SourceMap, SourceText};
+
+/// Collects diagnostics and writes them to every sink added with `add_sink`.
 pub struct Emitter {
     diag: Vec<Diagnostic>,
-    source_map: (),
+    /// Indices into `diag` by `Diagnostic::fingerprint`, to find duplicates.
+    seen: HashMap<u64, Vec<usize>>,
+    /// Diagnostics before this index were already written to the sinks.
+    flushed: usize,
+    source_map: SourceMap,
+    sinks: Vec<Box<dyn Sink>>,
+    group_by_rule: bool,
+    /// Diagnostics found in here are suppressed.
+    baseline: Option<Baseline>,
+    /// What reports say about each code.
+    rules: Vec<RuleMeta>,
+    /// The target diagnostics pushed now belong to.
+    target: Option<Arc<CargoTarget>>,
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Self {
+            diag: vec![],
+            seen: HashMap::default(),
+            flushed: 0,
+            source_map: SourceMap::default(),
+            sinks: vec![],
+            group_by_rule: false,
+            baseline: None,
+            rules: Registry::default().meta(),
+            target: None,
+        }
+    }
+}
+
+/// How diagnostics are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Annotated snippets.
+    Human,
+    /// One cargo `compiler-message` per line.
+    Json,
+    /// A single SARIF log, suppressed diagnostics included.
+    Sarif,
+    /// GitHub Actions workflow commands.
+    Github,
+    /// A checkstyle XML report.
+    Checkstyle,
+    /// A JUnit XML report.
+    Junit,
+}
+
+impl Default for MessageFormat {
+    fn default() -> Self { Self::Human }
 }
 
 impl Emitter {
-    pub fn found_errors(&self) -> bool { !self.diag.is_empty() }
+    pub fn found_errors(&self) -> bool { self.max_severity() == Some(Severity::Error) }
+
+    /// The most severe diagnostic emitted so far, suppressed diagnostics don't count.
+    pub fn max_severity(&self) -> Option<Severity> {
+        self.reported().map(|d| d.severity).max()
+    }
+
+    /// The exit code for the run, `1` if any errors were emitted.
+    pub fn exit_code(&self) -> i32 {
+        match self.max_severity() {
+            Some(Severity::Error) => 1,
+            _ => 0,
+        }
+    }
 
+    pub fn diagnostics(&self) -> &[Diagnostic] { &self.diag }
+
+    /// Every diagnostic that is not suppressed.
+    pub fn reported(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diag.iter().filter(|d| d.suppression.is_none())
+    }
+
+    /// A line counting reported diagnostics by severity then by rule and severity,
+    /// `None` if nothing was reported.
+    pub fn summary(&self) -> Option<String> {
+        summary(&ordered(self.diag.iter(), self.group_by_rule))
+    }
+
+    pub fn source_map(&self) -> &SourceMap { &self.source_map }
+
+    /// Write diagnostics to `sink` as well as every sink already added.
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) { self.sinks.push(sink); }
+
+    /// Order diagnostics by rule before file and position.
+    pub fn set_group_by_rule(&mut self, group: bool) { self.group_by_rule = group; }
+
+    /// Register the text of `file` so labels pointing into it can be rendered.
+    pub fn add_file(&mut self, file: &str, text: &str) {
+        self.source_map.add_file(file, || text.to_owned());
+    }
+
+    /// Register the text of the file `node` belongs to.
+    pub fn add_node_file(&mut self, file: &str, node: &SyntaxNode) {
+        self.source_map.add_file(file, || util::root_node(node).to_string());
+    }
+
+    /// Describe codes with `rules`, the `Registry::meta` of the registry files are
+    /// checked with, the built in rules are described by default.
+    pub fn set_rules(&mut self, rules: Vec<RuleMeta>) { self.rules = rules; }
+
+    /// Attribute the diagnostics pushed from now on to `target`.
+    pub fn set_target(&mut self, target: Option<CargoTarget>) {
+        self.target = target.map(Arc::new);
+    }
+
+    /// Suppress every diagnostic recorded in `baseline` from now on.
+    pub fn set_baseline(&mut self, baseline: Baseline) { self.baseline = Some(baseline); }
+
+    /// Add a diagnostic, one identical to a diagnostic already pushed is dropped so it
+    /// can't use up a second baseline entry.
+    pub fn push(&mut self, mut diag: Diagnostic) {
+        let (diags, seen) =
+            (&self.diag, self.seen.entry(diag.fingerprint()).or_default());
+        if seen.iter().any(|&i| diags[i].same_as(&diag)) {
+            return;
+        }
+        seen.push(self.diag.len());
+        if diag.target.is_none() {
+            diag.target = self.target.clone();
+        }
+        if let Some(baseline) = &mut self.baseline {
+            if baseline.take(&diag, &self.source_map) {
+                diag.suppression =
+                    Some(Suppression::External("in the baseline".to_owned()));
+            }
+        }
+        self.diag.push(diag);
+    }
+
+    /// Emit an error pointing at `span`, `node` is any node of the file `span` is in.
     pub fn sugg_with_span(
         &mut self,
+        code: &str,
         msg: &str,
         sugg: &str,
         span: TextRange,
         node: SyntaxNode,
         file: &str,
     ) {
-        self.diag.push(Diagnostic::Spanned(SpannedError {
-            msg: msg.to_owned(),
-            suggestion: sugg.to_owned(),
-            source: node,
-            span,
-            file: file.to_owned(),
-        }));
+        self.add_node_file(file, &node);
+        self.push(
+            Diagnostic::new(Severity::Error, code, msg, file)
+                .with_label(Label::primary(file, span, ""))
+                .with_suggestion(Suggestion::text(sugg)),
+        );
     }
 
-    pub fn simple_sugg(&mut self, msg: &str, sugg: &str, file: &str) {
-        self.diag.push(Diagnostic::Simple(SimpleError {
-            msg: msg.to_owned(),
-            file: file.to_owned(),
-            sugg: sugg.to_owned(),
-        }));
-    }
-
-    pub fn emit(self) -> std::io::Result<()> {
-        for err in self.diag {
-            match err {
-                Diagnostic::Spanned(spanned) => eprint!("{}", spanned.emit_error()),
-                Diagnostic::Simple(simple) => {
-                    eprintln!("error: {}", simple.msg);
-                    eprintln!("--> {}", simple.file);
-                    eprintln!("{}", simple.sugg);
+    /// Emit an error about a whole file.
+    pub fn simple_sugg(&mut self, code: &str, msg: &str, sugg: &str, file: &str) {
+        self.push(
+            Diagnostic::new(Severity::Error, code, msg, file)
+                .with_suggestion(Suggestion::text(sugg)),
+        );
+    }
+
+    /// A self contained HTML report of every reported diagnostic, `crates` is the name
+    /// and directory of every checked crate.
+    pub fn html_report(&self, crates: &[(String, PathBuf)]) -> String {
+        let diags = ordered(self.reported(), self.group_by_rule);
+        html::report(&diags, &self.source_map, &self.rules, crates)
+    }
+
+    /// Write every diagnostic pushed since the last flush to the sinks, call this when a
+    /// file is done so output streams while checking.
+    ///
+    /// Each flush is sorted like `emit` sorts, across flushes the output is in the order
+    /// files were checked. Files are checked in sorted order within a package and
+    /// packages in the order the workspace lists them.
+    ///
+    /// Nothing is written before `emit` when grouping by rule, a later file can still
+    /// add to any group.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.group_by_rule {
+            return Ok(());
+        }
+        let pending = ordered(self.diag[self.flushed..].iter(), false);
+        for diag in pending.iter().filter(|d| d.suppression.is_none()) {
+            for sink in &mut self.sinks {
+                sink.diagnostic(diag, &self.source_map, &self.rules)?;
+            }
+        }
+        self.flushed = self.diag.len();
+        Ok(())
+    }
+
+    /// Write everything left and finish every sink.
+    pub fn emit(mut self) -> io::Result<()> {
+        if self.group_by_rule {
+            let diags = ordered(self.diag.iter(), true);
+            for diag in diags.iter().filter(|d| d.suppression.is_none()) {
+                for sink in &mut self.sinks {
+                    sink.diagnostic(diag, &self.source_map, &self.rules)?;
                 }
             }
+        } else {
+            self.flush()?;
         }
 
+        let diags = ordered(self.diag.iter(), self.group_by_rule);
+        for sink in &mut self.sinks {
+            sink.finish(&diags, &self.source_map, &self.rules)?;
+        }
         Ok(())
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum Diagnostic {
-    Spanned(SpannedError),
-    Simple(SimpleError),
+/// `diags` in output order, sorted by file and position or by rule first when grouping,
+/// with duplicates removed.
+///
+/// The same diagnostic can be emitted more than once, i.e. once for each expansion of a
+/// macro.
+fn ordered<'a>(
+    diags: impl Iterator<Item = &'a Diagnostic>,
+    group_by_rule: bool,
+) -> Vec<&'a Diagnostic> {
+    let key = |d: &'a Diagnostic| {
+        let primary = d.primary();
+        let position = (
+            primary.map_or(d.file.as_str(), |l| l.file.as_str()),
+            primary.map(|l| (l.span.start(), l.span.end())),
+        );
+        let rule = if group_by_rule { Some(d.code.as_str()) } else { None };
+        (rule, position, d.code.as_str(), d.msg.as_str())
+    };
+
+    let mut sorted = diags.collect::<Vec<_>>();
+    sorted.sort_by_key(|d| key(*d));
+
+    let mut ordered: Vec<&Diagnostic> = vec![];
+    for diag in sorted {
+        // Duplicates share a key so only the run of equal keys needs checking.
+        let dup = ordered
+            .iter()
+            .rev()
+            .take_while(|o| key(**o) == key(diag))
+            .any(|o| *o == diag);
+        if !dup {
+            ordered.push(diag);
+        }
+    }
+    ordered
 }
 
-#[derive(Clone, Debug)]
-pub struct SimpleError {
+/// Count the reported `diags` by severity then by rule and severity, `None` if nothing
+/// was reported.
+fn summary(diags: &[&Diagnostic]) -> Option<String> {
+    let mut rules = BTreeMap::<&str, BTreeMap<Severity, usize>>::new();
+    for diag in diags.iter().filter(|d| d.suppression.is_none()) {
+        *rules.entry(&diag.code).or_default().entry(diag.severity).or_default() += 1;
+    }
+    if rules.is_empty() {
+        return None;
+    }
+
+    let mut total = BTreeMap::<Severity, usize>::new();
+    for counts in rules.values() {
+        for (severity, count) in counts {
+            *total.entry(*severity).or_default() += count;
+        }
+    }
+    let counts = |counts: &BTreeMap<Severity, usize>| {
+        counts
+            .iter()
+            .rev()
+            .map(|(severity, count)| {
+                format!("{} {}{}", count, severity, if *count == 1 { "" } else { "s" })
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut line = format!("summary: {}", counts(&total));
+    let suppressed = diags.iter().filter(|d| d.suppression.is_some()).count();
+    if suppressed != 0 {
+        line.push_str(&format!(", {} suppressed", suppressed));
+    }
+    for (code, by_severity) in &rules {
+        line.push_str(&format!("; {}: {}", code, counts(by_severity)));
+    }
+    Some(line)
+}
+
+/// How bad a diagnostic is, ordered from least to most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Help,
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Help => "help",
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A span of source with a message explaining its part in a diagnostic.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Label {
+    pub file: String,
+    pub span: TextRange,
     pub msg: String,
+    /// The primary label is where the diagnostic is reported.
+    pub primary: bool,
+}
+
+impl Label {
+    pub fn primary(file: &str, span: TextRange, msg: &str) -> Self {
+        Self { file: file.to_owned(), span, msg: msg.to_owned(), primary: true }
+    }
+
+    pub fn secondary(file: &str, span: TextRange, msg: &str) -> Self {
+        Self { file: file.to_owned(), span, msg: msg.to_owned(), primary: false }
+    }
+}
+
+/// How confident a suggestion is, mirroring rustc's levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Applicability {
+    /// The edits are definitely what the user wants and can be applied automatically.
+    MachineApplicable,
+    /// The edits may be what the user wants but should be reviewed.
+    MaybeIncorrect,
+    /// The edits contain placeholders the user has to fill in.
+    HasPlaceholders,
+    Unspecified,
+}
+
+/// Replace `span` in `file` with `replacement`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Edit {
     pub file: String,
-    pub sugg: String,
+    pub span: TextRange,
+    pub replacement: String,
 }
 
-#[derive(Clone, Debug)]
-pub struct SpannedError {
+/// A way to fix a diagnostic, with or without the edits to do it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Suggestion {
     pub msg: String,
-    pub suggestion: String,
-    pub source: SyntaxNode,
-    pub span: TextRange,
+    pub edits: Vec<Edit>,
+    pub applicability: Applicability,
+}
+
+impl Suggestion {
+    /// A suggestion only a human can apply.
+    pub fn text(msg: &str) -> Self {
+        Self {
+            msg: msg.to_owned(),
+            edits: vec![],
+            applicability: Applicability::Unspecified,
+        }
+    }
+
+    pub fn with_edits(msg: &str, edits: Vec<Edit>, applicability: Applicability) -> Self {
+        Self { msg: msg.to_owned(), edits, applicability }
+    }
+}
+
+/// Why a diagnostic is not reported, it is still kept for formats that track it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Suppression {
+    /// Allowed in the source, holds the justification.
+    InSource(String),
+    /// Allowed by something outside the source like a baseline file, holds the
+    /// justification.
+    External(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A stable code identifying what emitted this, i.e. `RC0002`.
+    pub code: String,
+    pub msg: String,
+    /// The file this diagnostic is about, labels may point into other files.
     pub file: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub suggestions: Vec<Suggestion>,
+    pub suppression: Option<Suppression>,
+    /// The package target being checked when this was emitted.
+    pub target: Option<Arc<CargoTarget>>,
 }
 
-impl SpannedError {
-    fn emit_error(&self) -> String {
-        let mut buffer = String::new();
-        let root = util::root_node(&self.source);
-        // TODO: do this once and use it by mapping to Span/TextRange?
-        let (row, col) = root.to_string().chars().take(self.span.start().into()).fold(
-            (0, 0),
-            |(mut r, mut c), ch| {
-                if ch == '\n' {
-                    r += 1;
-                    c = 0;
-                } else {
-                    c += 1;
-                }
-                (r, c)
-            },
-        );
+/// A package target the way cargo's JSON messages describe it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CargoTarget {
+    pub package_id: String,
+    pub manifest_path: String,
+    /// `lib` or `bin`.
+    pub kind: String,
+    pub name: String,
+    pub src_path: String,
+    pub edition: String,
+}
 
-        buffer.push_str(&self.msg);
-        buffer.push('\n');
-        buffer.push_str(&format!("--> {}:{}:{}\n", self.file, row, col));
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &str, msg: &str, file: &str) -> Self {
+        Self {
+            severity,
+            code: code.to_owned(),
+            msg: msg.to_owned(),
+            file: file.to_owned(),
+            labels: vec![],
+            notes: vec![],
+            suggestions: vec![],
+            suppression: None,
+            target: None,
+        }
+    }
 
-        let snippet = self.source.to_string() + "\n";
-        buffer.push_str(&snippet);
+    pub fn with_label(mut self, label: Label) -> Self {
+        self.labels.push(label);
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_owned());
+        self
+    }
+
+    /// Add a suggestion, one with an empty message and no edits is ignored.
+    pub fn with_suggestion(mut self, sugg: Suggestion) -> Self {
+        if !sugg.msg.is_empty() || !sugg.edits.is_empty() {
+            self.suggestions.push(sugg);
+        }
+        self
+    }
+
+    pub fn with_suppression(mut self, suppression: Suppression) -> Self {
+        self.suppression = Some(suppression);
+        self
+    }
 
-        buffer
+    /// Equal ignoring whether either is suppressed.
+    fn same_as(&self, other: &Diagnostic) -> bool {
+        (&self.severity, &self.code, &self.msg, &self.file)
+            == (&other.severity, &other.code, &other.msg, &other.file)
+            && (&self.labels, &self.notes, &self.suggestions)
+                == (&other.labels, &other.notes, &other.suggestions)
+    }
+
+    /// A hash of everything `same_as` compares.
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (&self.severity, &self.code, &self.msg, &self.file).hash(&mut hasher);
+        (&self.labels, &self.notes, &self.suggestions).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The label the diagnostic is reported at, `None` for diagnostics about a whole
+    /// file.
+    pub fn primary(&self) -> Option<&Label> {
+        self.labels.iter().find(|l| l.primary).or_else(|| self.labels.first())
+    }
+
+    fn snippet(&self) -> Snippet<'_> {
+        let mut footers =
+            self.notes.iter().map(|n| (FooterKind::Note, n.clone())).collect::<Vec<_>>();
+        for sugg in &self.suggestions {
+            let help = match sugg.edits.as_slice() {
+                // Show a short replacement inline like rustc does.
+                [edit] if !edit.replacement.contains('\n') && !sugg.msg.is_empty() => {
+                    format!("{}: `{}`", sugg.msg, edit.replacement)
+                }
+                _ => sugg.msg.clone(),
+            };
+            footers.push((FooterKind::Help, help));
+        }
+
+        Snippet {
+            level: self.severity,
+            code: Some(&self.code).filter(|c| !c.is_empty()).map(|c| c.as_str()),
+            title: &self.msg,
+            file: Some(&self.file),
+            annotations: self
+                .labels
+                .iter()
+                .map(|l| Annotation {
+                    file: &l.file,
+                    range: l.span,
+                    label: &l.msg,
+                    primary: l.primary,
+                })
+                .collect(),
+            footers,
+        }
     }
 }
 
@@ -116,3 +546,59 @@ mod util {
         }
     }
 }
+
+#[test]
+fn ordered_and_summarized() {
+    let span = |start: u32| TextRange::new(start.into(), (start + 1).into());
+    let at =
+        |code: &str, file: &str, start: u32| {
+            Diagnostic::new(Severity::Error, code, "msg", file)
+                .with_label(Label::primary(file, span(start), ""))
+        };
+    let diags = vec![
+        at("RC0002", "src/b.rs", 4),
+        at("RC0001", "src/b.rs", 1),
+        at("RC0002", "src/a.rs", 9),
+        at("RC0002", "src/b.rs", 4),
+        at("RC0001", "src/a.rs", 2)
+            .with_suppression(Suppression::External("baseline".to_owned())),
+        Diagnostic::new(Severity::Warning, "RC0001", "msg", "src/a.rs"),
+    ];
+
+    let order = |group_by_rule| {
+        ordered(diags.iter(), group_by_rule)
+            .iter()
+            .map(|d| (d.code.as_str(), d.file.as_str(), d.primary().map(|l| l.span)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        order(false),
+        vec![
+            ("RC0001", "src/a.rs", None),
+            ("RC0001", "src/a.rs", Some(span(2))),
+            ("RC0002", "src/a.rs", Some(span(9))),
+            ("RC0001", "src/b.rs", Some(span(1))),
+            ("RC0002", "src/b.rs", Some(span(4))),
+        ]
+    );
+    assert_eq!(order(true)[3], ("RC0002", "src/a.rs", Some(span(9))));
+
+    // Diagnostics stream to every sink as they are flushed.
+    let (human, json) = (Buffer::default(), Buffer::default());
+    let mut emitter = Emitter::default();
+    let root = PathBuf::from("/ws");
+    emitter.add_sink(sink(MessageFormat::Human, human.clone(), false, false, &root));
+    emitter.add_sink(sink(MessageFormat::Json, json.clone(), false, false, &root));
+    for diag in diags {
+        emitter.push(diag);
+    }
+    emitter.flush().unwrap();
+    assert!(human.contents().starts_with("warning[RC0001]: msg\n --> src/a.rs\n"));
+    assert_eq!(json.contents().lines().count(), 4);
+
+    emitter.emit().unwrap();
+    assert!(human.contents().ends_with(
+        "summary: 3 errors, 1 warning, 1 suppressed; RC0001: 1 error, 1 warning; \
+         RC0002: 2 errors\n"
+    ));
+}
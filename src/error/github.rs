@@ -0,0 +1,92 @@
+//! GitHub Actions workflow commands, the runner turns these into inline annotations on
+//! the pull request.
+
+use std::path::Path;
+
+use super::{Diagnostic, Severity, SourceMap};
+
+/// A single `::error file=...::message` line for `diag`, GitHub only annotates files
+/// named relative to `root`, the root of the repository.
+pub fn annotation(diag: &Diagnostic, source_map: &SourceMap, root: &Path) -> String {
+    let level = match diag.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note | Severity::Help => "notice",
+    };
+
+    let mut props = vec![];
+    let (file, span) = match diag.primary() {
+        Some(label) => (label.file.as_str(), Some(label.span)),
+        None => (diag.file.as_str(), None),
+    };
+    let relative = Path::new(file).strip_prefix(root).unwrap_or_else(|_| Path::new(file));
+    props.push(format!(
+        "file={}",
+        property(&relative.to_string_lossy().replace('\\', "/"))
+    ));
+    if let Some(span) = span {
+        if let (Some(start), Some(end)) = (
+            source_map.position(file, span.start()),
+            source_map.position(file, span.end()),
+        ) {
+            props.push(format!("line={}", start.line));
+            props.push(format!("col={}", start.col_char));
+            props.push(format!("endLine={}", end.line));
+            props.push(format!("endColumn={}", end.col_char));
+        }
+    }
+    if !diag.code.is_empty() {
+        props.push(format!("title={}", property(&diag.code)));
+    }
+
+    let mut msg = diag.msg.clone();
+    for note in &diag.notes {
+        msg.push_str("\nnote: ");
+        msg.push_str(note);
+    }
+    for sugg in diag.suggestions.iter().filter(|s| !s.msg.is_empty()) {
+        msg.push_str("\nhelp: ");
+        msg.push_str(&sugg.msg);
+    }
+
+    format!("::{} {}::{}\n", level, props.join(","), data(&msg))
+}
+
+/// Escape the message of a workflow command.
+fn data(text: &str) -> String {
+    text.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escape a property value, these also can't contain the separators.
+fn property(text: &str) -> String { data(text).replace(':', "%3A").replace(',', "%2C") }
+
+#[test]
+fn workflow_command() {
+    use syntax::TextRange;
+
+    use super::Label;
+
+    let root = Path::new("/ws");
+    let mut map = SourceMap::default();
+    map.add_file("/ws/src/main.rs", || "fn main() {\n    foo!(a);\n}\n".to_owned());
+
+    let diag =
+        Diagnostic::new(Severity::Warning, "RC0002", "bad, 100% bad", "/ws/src/main.rs")
+            .with_label(Label::primary(
+                "/ws/src/main.rs",
+                TextRange::new(16.into(), 23.into()),
+                "",
+            ))
+            .with_note("a note");
+    assert_eq!(
+        annotation(&diag, &map, root),
+        "::warning file=src/main.rs,line=2,col=5,endLine=2,endColumn=12,title=RC0002::\
+         bad, 100%25 bad%0Anote: a note\n"
+    );
+
+    let diag = Diagnostic::new(Severity::Error, "RC0001", "mod.rs", "src/a,b/mod.rs");
+    assert_eq!(
+        annotation(&diag, &map, root),
+        "::error file=src/a%2Cb/mod.rs,title=RC0001::mod.rs\n"
+    );
+}
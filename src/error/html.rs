@@ -0,0 +1,312 @@
+//! A self contained HTML report for triaging every diagnostic of a run in a browser.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use syntax::{TextRange, TextSize};
+
+use super::{Diagnostic, Label, Severity, SourceMap, SourceText};
+use crate::rules::RuleMeta;
+
+/// Lines shown before and after the labels of a diagnostic.
+const CONTEXT: u32 = 2;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.8em; text-align: left; }
+.diag { border: 1px solid #ccc; border-radius: 4px; margin: 1em 0; padding: 0.5em 1em; }
+.diag:target { border-color: #36c; }
+.error { color: #c00; } .warning { color: #a60; } .note, .help { color: #080; }
+pre { background: #f6f6f6; padding: 0.5em; overflow-x: auto; }
+.line { color: #888; user-select: none; }
+mark.primary { background: #fcc; } mark.secondary { background: #cde; }
+mark:target { outline: 2px solid #36c; }
+";
+
+/// The report for `diags`, `crates` holds the name and directory of every checked
+/// crate so diagnostics can be grouped by the crate they are in.
+pub fn report(
+    diags: &[&Diagnostic],
+    source_map: &SourceMap,
+    rules: &[RuleMeta],
+    crates: &[(String, PathBuf)],
+) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>ruma-check report</title>\n<style>{}</style>\n</head>\n<body>",
+        STYLE
+    )
+    .unwrap();
+    writeln!(out, "<h1>ruma-check report</h1>\n<p>{} diagnostics</p>", diags.len())
+        .unwrap();
+
+    // Per crate summary.
+    let mut by_crate = BTreeMap::<&str, BTreeMap<Severity, usize>>::new();
+    for diag in diags {
+        *by_crate
+            .entry(crate_of(&diag.file, crates))
+            .or_default()
+            .entry(diag.severity)
+            .or_default() += 1;
+    }
+    out.push_str("<h2>Crates</h2>\n<table>\n<tr><th>Crate</th>");
+    let levels = [Severity::Error, Severity::Warning, Severity::Note, Severity::Help];
+    for level in &levels {
+        write!(out, "<th>{}</th>", level).unwrap();
+    }
+    out.push_str("</tr>\n");
+    for (krate, counts) in &by_crate {
+        write!(out, "<tr><td>{}</td>", escape(krate)).unwrap();
+        for level in &levels {
+            write!(out, "<td>{}</td>", counts.get(level).copied().unwrap_or(0)).unwrap();
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table>\n");
+
+    // Per rule summary, linking to the first diagnostic of each rule.
+    let mut by_rule = BTreeMap::<&str, (usize, usize)>::new();
+    for (idx, diag) in diags.iter().enumerate() {
+        by_rule.entry(&diag.code).or_insert((0, idx)).0 += 1;
+    }
+    out.push_str(
+        "<h2>Rules</h2>\n<table>\n<tr><th>Code</th><th>Rule</th><th>Count</th></tr>\n",
+    );
+    for (code, (count, first)) in &by_rule {
+        let name = rules.iter().find(|r| r.code == *code).map_or("", |r| r.name.as_str());
+        writeln!(
+            out,
+            "<tr><td><a href=\"#d{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            first,
+            escape(code),
+            escape(name),
+            count
+        )
+        .unwrap();
+    }
+    out.push_str("</table>\n<h2>Diagnostics</h2>\n");
+
+    for (idx, diag) in diags.iter().enumerate() {
+        diagnostic(&mut out, idx, diag, source_map);
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn diagnostic(out: &mut String, idx: usize, diag: &Diagnostic, source_map: &SourceMap) {
+    let level = diag.severity.to_string();
+    writeln!(
+        out,
+        "<div class=\"diag\" id=\"d{}\">\n<p><b class=\"{}\">{}[{}]</b>: {}</p>",
+        idx,
+        level,
+        level,
+        escape(&diag.code),
+        escape(&diag.msg)
+    )
+    .unwrap();
+
+    let location = match diag.primary() {
+        Some(l) => match source_map.position(&l.file, l.span.start()) {
+            Some(pos) => format!("{}:{}:{}", l.file, pos.line, pos.col_char),
+            None => l.file.clone(),
+        },
+        None => diag.file.clone(),
+    };
+    writeln!(out, "<p>--&gt; {}</p>", escape(&location)).unwrap();
+
+    // Every file the labels point into, in the order they are first used.
+    let mut files: Vec<&str> = vec![];
+    for label in &diag.labels {
+        if !files.contains(&label.file.as_str()) {
+            files.push(&label.file);
+        }
+    }
+    for file in files {
+        let labels = diag
+            .labels
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.file == file)
+            .collect::<Vec<_>>();
+        match source_map.get(file) {
+            Some(source) => snippet(out, idx, source, &labels),
+            None => writeln!(out, "<p>{}</p>", escape(file)).unwrap(),
+        }
+    }
+
+    // Secondary labels are listed with a link to where they are highlighted.
+    let related = diag
+        .labels
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| !l.primary)
+        .map(|(j, l)| {
+            let line = source_map.position(&l.file, l.span.start()).map_or(0, |p| p.line);
+            format!(
+                "<li><a href=\"#d{}-l{}\">{}:{}</a> {}</li>",
+                idx,
+                j,
+                escape(&l.file),
+                line,
+                escape(&l.msg)
+            )
+        })
+        .collect::<String>();
+    if !related.is_empty() {
+        writeln!(out, "<p>related:</p>\n<ul>{}</ul>", related).unwrap();
+    }
+
+    for note in &diag.notes {
+        writeln!(out, "<p><b class=\"note\">note</b>: {}</p>", escape(note)).unwrap();
+    }
+    for sugg in &diag.suggestions {
+        writeln!(out, "<p><b class=\"help\">help</b>: {}</p>", escape(&sugg.msg))
+            .unwrap();
+        for edit in &sugg.edits {
+            let line =
+                source_map.position(&edit.file, edit.span.start()).map_or(0, |p| p.line);
+            writeln!(
+                out,
+                "<p>{}:{}</p>\n<pre>{}</pre>",
+                escape(&edit.file),
+                line,
+                escape(&edit.replacement)
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("</div>\n");
+}
+
+/// The lines around `labels` with every label highlighted.
+fn snippet(
+    out: &mut String,
+    idx: usize,
+    source: &SourceText,
+    labels: &[(usize, &Label)],
+) {
+    let lines = |l: &Label| {
+        (
+            source.lines.position(l.span.start()).line,
+            source.lines.position(l.span.end()).line,
+        )
+    };
+    let first = labels.iter().map(|(_, l)| lines(l).0).min().unwrap_or(1);
+    let last = labels.iter().map(|(_, l)| lines(l).1).max().unwrap_or(1);
+    let from = first.saturating_sub(CONTEXT).max(1);
+    let to = (last + CONTEXT).min(source.lines.line_count());
+
+    // Highlights can't overlap, primary labels win then whichever starts first.
+    let mut marks = labels.to_vec();
+    marks.sort_by_key(|(_, l)| (!l.primary, l.span.start()));
+    let mut kept: Vec<(usize, &Label)> = vec![];
+    for mark in marks {
+        if kept
+            .iter()
+            .all(|(_, k)| k.span.intersect(mark.1.span).map_or(true, |i| i.is_empty()))
+        {
+            kept.push(mark);
+        }
+    }
+    kept.sort_by_key(|(_, l)| l.span.start());
+
+    out.push_str("<pre>");
+    for line in from..=to {
+        let start = source.lines.offset_utf16(line, 1).unwrap_or_default();
+        let text = source.line(line);
+        let end = start + TextSize::of(text);
+        write!(out, "<span class=\"line\">{:>4} | </span>", line).unwrap();
+
+        let mut pos = start;
+        for (j, label) in &kept {
+            let span = match label.span.intersect(TextRange::new(start, end)) {
+                Some(span) if !span.is_empty() => span,
+                _ => continue,
+            };
+            out.push_str(&escape(&text[slice(start, pos, span.start())]));
+            // Only the first line of a label gets the id other labels link to.
+            let id = if span.start() == label.span.start() {
+                format!(" id=\"d{}-l{}\"", idx, j)
+            } else {
+                String::new()
+            };
+            write!(
+                out,
+                "<mark class=\"{}\"{} title=\"{}\">{}</mark>",
+                if label.primary { "primary" } else { "secondary" },
+                id,
+                escape(&label.msg),
+                escape(&text[slice(start, span.start(), span.end())])
+            )
+            .unwrap();
+            pos = span.end();
+        }
+        out.push_str(&escape(&text[slice(start, pos, end)]));
+        out.push('\n');
+    }
+    out.push_str("</pre>\n");
+}
+
+/// The range from `from` to `to` relative to a line starting at `line`.
+fn slice(line: TextSize, from: TextSize, to: TextSize) -> std::ops::Range<usize> {
+    usize::from(from - line)..usize::from(to - line)
+}
+
+/// The checked crate `file` is in, the crate with the longest matching directory wins.
+fn crate_of<'a>(file: &str, crates: &'a [(String, PathBuf)]) -> &'a str {
+    crates
+        .iter()
+        .filter(|(_, dir)| Path::new(file).starts_with(dir))
+        .max_by_key(|(_, dir)| dir.components().count())
+        .map_or("(unknown)", |(name, _)| name.as_str())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[test]
+fn html_report() {
+    let text = "fn main() {\n    let a = 1;\n    foo!(<a>);\n}\n";
+    let file = "/ws/ruma-common/src/main.rs";
+    let mut map = SourceMap::default();
+    map.add_file(file, || text.to_owned());
+    let range = |s: &str| {
+        let start = text.find(s).unwrap() as u32;
+        TextRange::new(start.into(), (start + s.len() as u32).into())
+    };
+
+    let diag = Diagnostic::new(Severity::Error, "RC0002", "bad macro", file)
+        .with_label(Label::primary(file, range("foo!(<a>)"), "called here"))
+        .with_label(Label::secondary(file, range("a = 1"), "defined here"));
+    let rules = vec![RuleMeta {
+        code: "RC0002",
+        name: "Correct formatting of macro calls.".to_owned(),
+        severity: Severity::Error,
+        doc: String::new(),
+    }];
+    let crates = vec![
+        ("ruma".to_owned(), PathBuf::from("/ws")),
+        ("ruma-common".to_owned(), PathBuf::from("/ws/ruma-common")),
+    ];
+
+    let html = report(&[&diag], &map, &rules, &crates);
+    assert!(html.contains("<tr><td>ruma-common</td><td>1</td>"));
+    assert!(html.contains("<td>Correct formatting of macro calls.</td>"));
+    assert!(html.contains(
+        "<mark class=\"primary\" id=\"d0-l0\" title=\"called here\">foo!(&lt;a&gt;)</mark>;"
+    ));
+    assert!(html
+        .contains("<a href=\"#d0-l1\">/ws/ruma-common/src/main.rs:2</a> defined here"));
+}
@@ -0,0 +1,213 @@
+//! Diagnostics in the JSON shape `cargo check --message-format=json` emits, so existing
+//! tooling like rust-analyzer's check command can consume them.
+
+use serde_json::{json, Value};
+use syntax::TextRange;
+
+use super::{render, Applicability, Diagnostic, Severity, SourceMap};
+
+/// A single line of JSON for `diag`, a `compiler-message` wrapping the rustc diagnostic.
+/// The package fields are left out when `diag` wasn't emitted for a package.
+pub fn message(diag: &Diagnostic, source_map: &SourceMap) -> Value {
+    let mut message = json!({
+        "reason": "compiler-message",
+        "message": diagnostic(diag, source_map),
+    });
+    if let Some(target) = &diag.target {
+        message["package_id"] = json!(target.package_id);
+        message["manifest_path"] = json!(target.manifest_path);
+        message["target"] = json!({
+            "kind": [target.kind],
+            "crate_types": [target.kind],
+            "name": target.name,
+            "src_path": target.src_path,
+            "edition": target.edition,
+            "doc": target.kind == "lib",
+            "doctest": target.kind == "lib",
+            "test": true,
+        });
+    }
+    message
+}
+
+/// The rustc diagnostic for `diag`.
+fn diagnostic(diag: &Diagnostic, source_map: &SourceMap) -> Value {
+    let mut children = diag
+        .notes
+        .iter()
+        .map(|note| child(Severity::Note, note, vec![]))
+        .collect::<Vec<_>>();
+    for sugg in &diag.suggestions {
+        let spans = sugg
+            .edits
+            .iter()
+            .map(|edit| {
+                let mut span = span(source_map, &edit.file, edit.span, true, None);
+                span["suggested_replacement"] = json!(edit.replacement);
+                span["suggestion_applicability"] =
+                    json!(applicability(sugg.applicability));
+                span
+            })
+            .collect();
+        children.push(child(Severity::Help, &sugg.msg, spans));
+    }
+
+    json!({
+        "$message_type": "diagnostic",
+        "message": diag.msg,
+        "code": if diag.code.is_empty() {
+            Value::Null
+        } else {
+            json!({ "code": diag.code, "explanation": null })
+        },
+        "level": diag.severity.to_string(),
+        "spans": diag
+            .labels
+            .iter()
+            .map(|l| span(source_map, &l.file, l.span, l.primary, Some(&l.msg)))
+            .collect::<Vec<_>>(),
+        "children": children,
+        "rendered": render::render(&diag.snippet(), source_map, false),
+    })
+}
+
+fn child(level: Severity, msg: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "message": msg,
+        "code": null,
+        "level": level.to_string(),
+        "spans": spans,
+        "children": [],
+        "rendered": null,
+    })
+}
+
+fn applicability(app: Applicability) -> &'static str {
+    match app {
+        Applicability::MachineApplicable => "MachineApplicable",
+        Applicability::MaybeIncorrect => "MaybeIncorrect",
+        Applicability::HasPlaceholders => "HasPlaceholders",
+        Applicability::Unspecified => "Unspecified",
+    }
+}
+
+/// A rustc span, lines and columns are 1 based with columns counted in chars.
+fn span(
+    source_map: &SourceMap,
+    file: &str,
+    range: TextRange,
+    primary: bool,
+    label: Option<&str>,
+) -> Value {
+    let (start, end, text) = match source_map.get(file) {
+        Some(source) => {
+            let start = source.lines.position(range.start());
+            let end = source.lines.position(range.end());
+            let text = (start.line..=end.line)
+                .map(|line| {
+                    let text = source.line(line);
+                    let highlight_start =
+                        if line == start.line { start.col_char } else { 1 };
+                    let highlight_end = if line == end.line {
+                        end.col_char
+                    } else {
+                        text.chars().count() as u32 + 1
+                    };
+                    json!({
+                        "text": text,
+                        "highlight_start": highlight_start,
+                        "highlight_end": highlight_end,
+                    })
+                })
+                .collect::<Vec<_>>();
+            ((start.line, start.col_char), (end.line, end.col_char), text)
+        }
+        None => ((1, 1), (1, 1), vec![]),
+    };
+
+    json!({
+        "file_name": file,
+        "byte_start": u32::from(range.start()),
+        "byte_end": u32::from(range.end()),
+        "line_start": start.0,
+        "line_end": end.0,
+        "column_start": start.1,
+        "column_end": end.1,
+        "is_primary": primary,
+        "text": text,
+        "label": label.filter(|l| !l.is_empty()),
+        "suggested_replacement": null,
+        "suggestion_applicability": null,
+        "expansion": null,
+    })
+}
+
+#[test]
+fn rustc_shape() {
+    use super::{Edit, Label, Suggestion};
+
+    let text = "fn main() {\n    foo!(a);\n}\n";
+    let mut map = SourceMap::default();
+    map.add_file("src/main.rs", || text.to_owned());
+
+    let span = TextRange::new(16.into(), 23.into());
+    let diag = Diagnostic::new(Severity::Warning, "RC0002", "bad macro", "src/main.rs")
+        .with_label(Label::primary("src/main.rs", span, "here"))
+        .with_note("a note")
+        .with_suggestion(Suggestion::with_edits(
+            "use bar",
+            vec![Edit {
+                file: "src/main.rs".to_owned(),
+                span,
+                replacement: "bar!(a)".to_owned(),
+            }],
+            Applicability::MachineApplicable,
+        ));
+
+    let json = diagnostic(&diag, &map);
+    assert_eq!(json["level"], "warning");
+    assert_eq!(json["code"]["code"], "RC0002");
+    assert_eq!(json["spans"][0]["line_start"], 2);
+    assert_eq!(json["spans"][0]["column_start"], 5);
+    assert_eq!(json["spans"][0]["column_end"], 12);
+    assert_eq!(json["spans"][0]["label"], "here");
+    assert_eq!(json["spans"][0]["text"][0]["text"], "    foo!(a);");
+    assert_eq!(json["children"][0]["level"], "note");
+    assert_eq!(json["children"][1]["spans"][0]["suggested_replacement"], "bar!(a)");
+    assert_eq!(
+        json["children"][1]["spans"][0]["suggestion_applicability"],
+        "MachineApplicable"
+    );
+    assert!(json["rendered"].as_str().unwrap().starts_with("warning[RC0002]: bad macro"));
+
+    let message = message(&diag, &map);
+    assert_eq!(message["reason"], "compiler-message");
+    assert_eq!(message["message"], json);
+    assert!(message.get("package_id").is_none());
+}
+
+#[test]
+fn cargo_message() {
+    use std::sync::Arc;
+
+    use super::CargoTarget;
+
+    let mut diag = Diagnostic::new(Severity::Warning, "RC0001", "bad", "src/lib.rs");
+    diag.target = Some(Arc::new(CargoTarget {
+        package_id: "ruma-common 0.2.0 (path+file:///ws/ruma-common)".to_owned(),
+        manifest_path: "/ws/ruma-common/Cargo.toml".to_owned(),
+        kind: "lib".to_owned(),
+        name: "ruma_common".to_owned(),
+        src_path: "/ws/ruma-common/src/lib.rs".to_owned(),
+        edition: "2018".to_owned(),
+    }));
+
+    let message = message(&diag, &SourceMap::default());
+    assert_eq!(message["reason"], "compiler-message");
+    assert_eq!(message["package_id"], "ruma-common 0.2.0 (path+file:///ws/ruma-common)");
+    assert_eq!(message["manifest_path"], "/ws/ruma-common/Cargo.toml");
+    assert_eq!(message["target"]["kind"][0], "lib");
+    assert_eq!(message["target"]["name"], "ruma_common");
+    assert_eq!(message["target"]["edition"], "2018");
+    assert_eq!(message["message"]["code"]["code"], "RC0001");
+}
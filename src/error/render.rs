@@ -0,0 +1,336 @@
+//! Render diagnostics the way rustc does, with a line number gutter and the spans
+//! underlined in the source.
+
+use std::{collections::BTreeSet, fmt::Write};
+
+use syntax::TextRange;
+
+use super::{Position, Severity, SourceMap, SourceText};
+
+/// Lines shown before and after each annotated span.
+const CONTEXT: u32 = 1;
+/// Spans covering more lines than this only show the lines they start and end on.
+const MAX_SPAN_LINES: u32 = 6;
+
+/// When to color the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl Default for ColorChoice {
+    fn default() -> Self { Self::Auto }
+}
+
+impl ColorChoice {
+    /// Should output to stderr be colored.
+    pub fn stderr(self) -> bool {
+        match self {
+            ColorChoice::Auto => atty::is(atty::Stream::Stderr),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// A span of source to underline.
+#[derive(Clone, Copy, Debug)]
+pub struct Annotation<'a> {
+    pub file: &'a str,
+    pub range: TextRange,
+    pub label: &'a str,
+    /// The primary span is underlined with `^` every other span with `-`.
+    pub primary: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FooterKind {
+    Help,
+    Note,
+}
+
+/// Everything needed to render a single diagnostic.
+#[derive(Clone, Debug)]
+pub struct Snippet<'a> {
+    pub level: Severity,
+    /// The diagnostic code shown after the level, i.e. `error[RC0002]`.
+    pub code: Option<&'a str>,
+    pub title: &'a str,
+    /// The file a diagnostic without any annotations points to.
+    pub file: Option<&'a str>,
+    pub annotations: Vec<Annotation<'a>>,
+    pub footers: Vec<(FooterKind, String)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn paint(self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    fn level(self, level: Severity, code: Option<&str>) -> String {
+        let text = match code {
+            Some(code) => format!("{}[{}]", level, code),
+            None => level.to_string(),
+        };
+        self.paint(level_code(level), &text)
+    }
+
+    fn bold(self, text: &str) -> String { self.paint("1", text) }
+
+    fn gutter(self, text: &str) -> String { self.paint("1;34", text) }
+}
+
+/// Render `snippet` using the files in `source_map`.
+pub fn render(snippet: &Snippet<'_>, source_map: &SourceMap, color: bool) -> String {
+    let style = Style { color };
+    let mut buffer = String::new();
+    writeln!(
+        buffer,
+        "{}{}",
+        style.level(snippet.level, snippet.code),
+        style.bold(&format!(": {}", snippet.title))
+    )
+    .unwrap();
+
+    // The primary span's file comes first, then every other file in the order they
+    // were annotated.
+    let mut files = vec![];
+    let primary = snippet.annotations.iter().filter(|a| a.primary);
+    for ann in primary.chain(snippet.annotations.iter()) {
+        if !files.contains(&ann.file) {
+            files.push(ann.file);
+        }
+    }
+
+    let groups = files
+        .iter()
+        .map(|file| {
+            let anns = snippet.annotations.iter().filter(|a| a.file == *file);
+            let source = source_map.get(file);
+            let lines = source.map(|s| shown_lines(s, anns.clone())).unwrap_or_default();
+            (*file, source, anns.collect::<Vec<_>>(), lines)
+        })
+        .collect::<Vec<_>>();
+    let width = groups
+        .iter()
+        .filter_map(|(_, _, _, lines)| lines.iter().last())
+        .map(|line| line.to_string().len())
+        .max()
+        .unwrap_or(1);
+    let pad = " ".repeat(width);
+
+    if groups.is_empty() {
+        if let Some(file) = snippet.file {
+            writeln!(buffer, "{}{} {}", pad, style.gutter("-->"), file).unwrap();
+        }
+    }
+
+    for (idx, (file, source, anns, lines)) in groups.iter().enumerate() {
+        let arrow = if idx == 0 { "-->" } else { ":::" };
+        let source = match source {
+            Some(source) => source,
+            None => {
+                writeln!(buffer, "{}{} {}", pad, style.gutter(arrow), file).unwrap();
+                continue;
+            }
+        };
+
+        let first = anns.iter().find(|a| a.primary).unwrap_or(&anns[0]);
+        let pos = source.lines.position(first.range.start());
+        writeln!(
+            buffer,
+            "{}{} {}:{}:{}",
+            pad,
+            style.gutter(arrow),
+            file,
+            pos.line,
+            pos.col_char
+        )
+        .unwrap();
+
+        writeln!(buffer, "{} {}", pad, style.gutter("|")).unwrap();
+        let mut last = None;
+        for &line in lines {
+            if matches!(last, Some(last) if line > last + 1) {
+                writeln!(buffer, "{}", style.gutter("...")).unwrap();
+            }
+            last = Some(line);
+
+            let text = source.line(line);
+            writeln!(
+                buffer,
+                "{} {}",
+                style.gutter(&format!("{:>w$} |", line, w = width)),
+                text
+            )
+            .unwrap();
+
+            for ann in anns {
+                let start = source.lines.position(ann.range.start());
+                let end = source.lines.position(ann.range.end());
+                if let Some(underline) = underline(text, line, start, end) {
+                    let (mark, code) = if ann.primary {
+                        ('^', level_code(snippet.level))
+                    } else {
+                        ('-', "1;34")
+                    };
+                    let label = if line == end.line { ann.label } else { "" };
+                    let marks = format!(
+                        "{}{}",
+                        std::iter::repeat(mark).take(underline.1).collect::<String>(),
+                        if label.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" {}", label)
+                        }
+                    );
+                    writeln!(
+                        buffer,
+                        "{} {} {}{}",
+                        pad,
+                        style.gutter("|"),
+                        underline.0,
+                        style.paint(code, &marks)
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        writeln!(buffer, "{} {}", pad, style.gutter("|")).unwrap();
+    }
+
+    for (kind, text) in &snippet.footers {
+        let kind = match kind {
+            FooterKind::Help => "help",
+            FooterKind::Note => "note",
+        };
+        writeln!(buffer, "{} {} {}: {}", pad, style.gutter("="), style.bold(kind), text)
+            .unwrap();
+    }
+
+    buffer.push('\n');
+    buffer
+}
+
+fn level_code(level: Severity) -> &'static str {
+    match level {
+        Severity::Error => "1;31",
+        Severity::Warning => "1;33",
+        Severity::Note => "1;32",
+        Severity::Help => "1;36",
+    }
+}
+
+/// The 1 based line numbers to print for the annotations of one file.
+fn shown_lines<'a>(
+    source: &SourceText,
+    anns: impl Iterator<Item = &'a Annotation<'a>>,
+) -> BTreeSet<u32> {
+    let count = source.lines.line_count();
+    let mut lines = BTreeSet::new();
+    for ann in anns {
+        let start = source.lines.position(ann.range.start()).line;
+        let end = source.lines.position(ann.range.end()).line;
+
+        let mut add = |from: u32, to: u32| {
+            lines.extend(from.saturating_sub(CONTEXT).max(1)..=(to + CONTEXT).min(count))
+        };
+        if end - start >= MAX_SPAN_LINES {
+            add(start, start);
+            add(end, end);
+        } else {
+            add(start, end);
+        }
+    }
+    lines
+}
+
+/// The whitespace before the underline and how many marks to draw under `line`.
+///
+/// Tabs are kept in the whitespace so the marks line up with the source.
+fn underline(
+    text: &str,
+    line: u32,
+    start: Position,
+    end: Position,
+) -> Option<(String, usize)> {
+    if line < start.line || line > end.line {
+        return None;
+    }
+
+    let chars = text.chars().collect::<Vec<_>>();
+    let from = if line == start.line {
+        start.col_char as usize - 1
+    } else {
+        chars.iter().take_while(|c| c.is_whitespace()).count()
+    };
+    let to = if line == end.line { end.col_char as usize - 1 } else { chars.len() };
+    // An empty span still gets a single mark.
+    let len = to.saturating_sub(from).max(1);
+
+    let indent =
+        chars.iter().take(from).map(|c| if *c == '\t' { '\t' } else { ' ' }).collect();
+    Some((indent, len))
+}
+
+#[test]
+fn render_primary_and_secondary() {
+    let text = "fn main() {\n    let a = 1;\n    foo!(a);\n}\n";
+    let mut map = SourceMap::default();
+    map.add_file("src/main.rs", || text.to_owned());
+
+    let range = |s: &str| {
+        let start = text.find(s).unwrap() as u32;
+        TextRange::new(start.into(), (start + s.len() as u32).into())
+    };
+    let snippet = Snippet {
+        level: Severity::Error,
+        code: Some("RC0002"),
+        title: "bad macro",
+        file: Some("src/main.rs"),
+        annotations: vec![
+            Annotation {
+                file: "src/main.rs",
+                range: range("foo!(a)"),
+                label: "called here",
+                primary: true,
+            },
+            Annotation {
+                file: "src/main.rs",
+                range: range("a = 1"),
+                label: "defined here",
+                primary: false,
+            },
+        ],
+        footers: vec![(FooterKind::Note, "seen twice".to_owned())],
+    };
+
+    assert_eq!(
+        render(&snippet, &map, false),
+        r#"error[RC0002]: bad macro
+ --> src/main.rs:3:5
+  |
+1 | fn main() {
+2 |     let a = 1;
+  |         ----- defined here
+3 |     foo!(a);
+  |     ^^^^^^^ called here
+4 | }
+  |
+  = note: seen twice
+
+"#
+    );
+}
@@ -0,0 +1,300 @@
+//! A SARIF 2.1 log of every diagnostic for code scanning dashboards.
+
+use std::path::Path;
+
+use serde_json::{json, Value};
+use syntax::TextRange;
+
+use super::{Diagnostic, Severity, SourceMap, Suppression};
+use crate::rules::RuleMeta;
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+/// The base of the URIs of files in the repository.
+const SRCROOT: &str = "%SRCROOT%";
+
+/// The whole log, one run with `rules` describing every code that may be reported.
+/// Files in `root` get URIs relative to it.
+pub fn log(
+    diags: &[&Diagnostic],
+    source_map: &SourceMap,
+    rules: &[RuleMeta],
+    root: &Path,
+) -> Value {
+    json!({
+        "$schema": SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules.iter().map(rule).collect::<Vec<_>>(),
+                }
+            },
+            "originalUriBaseIds": { SRCROOT: { "uri": format!("{}/", file_uri(root)) } },
+            "columnKind": "utf16CodeUnits",
+            "results": diags
+                .iter()
+                .map(|diag| result(diag, source_map, rules, root))
+                .collect::<Vec<_>>(),
+        }],
+    })
+}
+
+fn rule(meta: &RuleMeta) -> Value {
+    let mut rule = json!({
+        "id": meta.code,
+        "shortDescription": { "text": meta.name },
+        "defaultConfiguration": { "level": level(meta.severity) },
+    });
+    if !meta.doc.is_empty() {
+        rule["fullDescription"] = json!({ "text": meta.doc });
+    }
+    rule
+}
+
+fn result(
+    diag: &Diagnostic,
+    source_map: &SourceMap,
+    rules: &[RuleMeta],
+    root: &Path,
+) -> Value {
+    // SARIF has nowhere to put notes so they are added to the message.
+    let mut text = diag.msg.clone();
+    for note in &diag.notes {
+        text.push_str("\nnote: ");
+        text.push_str(note);
+    }
+
+    let mut result = json!({
+        "ruleId": diag.code,
+        "level": level(diag.severity),
+        "message": { "text": text },
+        "locations": [match diag.primary() {
+            Some(label) => location(source_map, root, &label.file, Some(label.span)),
+            None => location(source_map, root, &diag.file, None),
+        }],
+    });
+
+    if let Some(idx) = rules.iter().position(|r| r.code == diag.code) {
+        result["ruleIndex"] = json!(idx);
+    }
+
+    let related = diag
+        .labels
+        .iter()
+        .filter(|l| !l.primary)
+        .enumerate()
+        .map(|(id, l)| {
+            let mut loc = location(source_map, root, &l.file, Some(l.span));
+            loc["id"] = json!(id);
+            loc["message"] = json!({ "text": l.msg });
+            loc
+        })
+        .collect::<Vec<_>>();
+    if !related.is_empty() {
+        result["relatedLocations"] = json!(related);
+    }
+
+    // A fix with an edit in a file whose text isn't known can't say what to replace.
+    let fixes = diag
+        .suggestions
+        .iter()
+        .filter(|s| !s.edits.is_empty())
+        .filter_map(|sugg| {
+            let changes = sugg
+                .edits
+                .iter()
+                .map(|edit| {
+                    Some(json!({
+                        "artifactLocation": artifact(root, &edit.file),
+                        "replacements": [{
+                            "deletedRegion": region(source_map, &edit.file, edit.span)?,
+                            "insertedContent": { "text": edit.replacement },
+                        }],
+                    }))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(json!({ "description": { "text": sugg.msg }, "artifactChanges": changes }))
+        })
+        .collect::<Vec<_>>();
+    if !fixes.is_empty() {
+        result["fixes"] = json!(fixes);
+    }
+
+    // An empty list says the result was checked for suppressions and had none.
+    result["suppressions"] = match &diag.suppression {
+        Some(Suppression::InSource(why)) => {
+            json!([{ "kind": "inSource", "justification": why }])
+        }
+        Some(Suppression::External(why)) => {
+            json!([{ "kind": "external", "justification": why }])
+        }
+        None => json!([]),
+    };
+
+    result
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note | Severity::Help => "note",
+    }
+}
+
+/// A location in `file`, the whole file when there is no `span`.
+fn location(
+    source_map: &SourceMap,
+    root: &Path,
+    file: &str,
+    span: Option<TextRange>,
+) -> Value {
+    let mut physical = json!({ "artifactLocation": artifact(root, file) });
+    if let Some(region) = span.and_then(|span| region(source_map, file, span)) {
+        physical["region"] = region;
+    }
+    json!({ "physicalLocation": physical })
+}
+
+/// The region of `span`, `None` when the text of `file` isn't known.
+///
+/// Offsets are counted in UTF-16 code units like columns, see `columnKind`.
+fn region(source_map: &SourceMap, file: &str, span: TextRange) -> Option<Value> {
+    let source = source_map.get(file)?;
+    let (start, end) =
+        (source.lines.position(span.start()), source.lines.position(span.end()));
+    let mut region = json!({
+        "startLine": start.line,
+        "startColumn": start.col_utf16,
+        "endLine": end.line,
+        "endColumn": end.col_utf16,
+    });
+    let utf16 = |text: &str| text.encode_utf16().count();
+    let (text, range) =
+        (&source.text, usize::from(span.start())..usize::from(span.end()));
+    if let (Some(before), Some(spanned)) = (text.get(..range.start), text.get(range)) {
+        region["charOffset"] = json!(utf16(before));
+        region["charLength"] = json!(utf16(spanned));
+    }
+    Some(region)
+}
+
+/// Where `file` is, relative to `SRCROOT` when it is in `root`.
+fn artifact(root: &Path, file: &str) -> Value {
+    let path = Path::new(file);
+    match path.strip_prefix(root) {
+        Ok(relative) => {
+            json!({ "uri": encode(&relative.to_string_lossy()), "uriBaseId": SRCROOT })
+        }
+        Err(_) if path.is_absolute() => json!({ "uri": file_uri(path) }),
+        Err(_) => json!({ "uri": encode(file) }),
+    }
+}
+
+/// The `file://` URI of the absolute `path`.
+fn file_uri(path: &Path) -> String {
+    let path = encode(&path.to_string_lossy());
+    // Windows paths start with the drive and need a slash before it.
+    if path.starts_with('/') {
+        format!("file://{}", path)
+    } else {
+        format!("file:///{}", path)
+    }
+}
+
+/// A path as the path of a URI, separators become `/` and everything else that isn't
+/// unreserved is percent encoded.
+fn encode(path: &str) -> String {
+    let mut uri = String::new();
+    for byte in path.replace('\\', "/").bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+#[test]
+fn sarif_log() {
+    use super::{Applicability, Edit, Label, Suggestion};
+
+    let text = "// é\nfn main() {\n    let a = 1;\n    foo!(a);\n}\n";
+    let mut map = SourceMap::default();
+    map.add_file("src/main.rs", || text.to_owned());
+    let range = |s: &str| {
+        let start = text.find(s).unwrap() as u32;
+        TextRange::new(start.into(), (start + s.len() as u32).into())
+    };
+
+    let rules = vec![RuleMeta {
+        code: "RC0002",
+        name: "Correct formatting of macro calls.".to_owned(),
+        severity: Severity::Error,
+        doc: "Macro calls are formatted like rustfmt formats function calls.".to_owned(),
+    }];
+    let diags = vec![
+        Diagnostic::new(Severity::Error, "RC0002", "bad macro", "src/main.rs")
+            .with_label(Label::primary("src/main.rs", range("foo!(a)"), ""))
+            .with_label(Label::secondary("src/main.rs", range("a = 1"), "defined here"))
+            .with_suggestion(Suggestion::with_edits(
+                "use bar",
+                vec![Edit {
+                    file: "src/main.rs".to_owned(),
+                    span: range("foo"),
+                    replacement: "bar".to_owned(),
+                }],
+                Applicability::MachineApplicable,
+            )),
+        Diagnostic::new(Severity::Warning, "RC0001", "mod.rs", "src/a/mod.rs")
+            .with_suppression(Suppression::External("baseline".to_owned())),
+    ];
+
+    let log = log(&diags.iter().collect::<Vec<_>>(), &map, &rules, Path::new("/ws"));
+    let run = &log["runs"][0];
+    assert_eq!(run["originalUriBaseIds"][SRCROOT]["uri"], "file:///ws/");
+    assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "RC0002");
+    assert!(run["tool"]["driver"]["rules"][0]["fullDescription"]["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Macro calls"));
+
+    let first = &run["results"][0];
+    assert_eq!(first["ruleIndex"], 0);
+    let region = &first["locations"][0]["physicalLocation"]["region"];
+    assert_eq!(
+        (region["startLine"].clone(), region["startColumn"].clone()),
+        (4.into(), 5.into())
+    );
+    // `é` is two bytes but one UTF-16 code unit.
+    assert_eq!(region["charOffset"], text.find("foo!(a)").unwrap() - 1);
+    assert_eq!(region["charLength"], 7);
+    assert_eq!(first["relatedLocations"][0]["message"]["text"], "defined here");
+    let change = &first["fixes"][0]["artifactChanges"][0];
+    assert_eq!(change["replacements"][0]["insertedContent"]["text"], "bar");
+    assert_eq!(first["suppressions"], json!([]));
+
+    let second = &run["results"][1];
+    assert!(second.get("ruleIndex").is_none());
+    assert!(second["locations"][0]["physicalLocation"].get("region").is_none());
+    assert_eq!(second["suppressions"][0]["kind"], "external");
+
+    assert_eq!(
+        artifact(Path::new("/ws"), "/ws/src/a b.rs"),
+        json!({ "uri": "src/a%20b.rs", "uriBaseId": SRCROOT })
+    );
+    assert_eq!(
+        artifact(Path::new("/ws"), "/other/lib.rs"),
+        json!({ "uri": "file:///other/lib.rs" })
+    );
+}
@@ -0,0 +1,209 @@
+//! Where diagnostics are written, an `Emitter` writes to any number of sinks at once.
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    github, json, render, sarif, summary, xml, Diagnostic, MessageFormat, SourceMap,
+};
+use crate::rules::RuleMeta;
+
+/// A destination for diagnostics.
+pub trait Sink {
+    /// Write a reported diagnostic, called in output order as soon as each file is done.
+    /// `rules` describes every code the emitter knows.
+    fn diagnostic(
+        &mut self,
+        diag: &Diagnostic,
+        source_map: &SourceMap,
+        rules: &[RuleMeta],
+    ) -> io::Result<()>;
+
+    /// Called once after the last diagnostic with every diagnostic in output order,
+    /// suppressed diagnostics included.
+    fn finish(
+        &mut self,
+        diags: &[&Diagnostic],
+        source_map: &SourceMap,
+        rules: &[RuleMeta],
+    ) -> io::Result<()>;
+}
+
+/// A sink writing `format` to `out`, `color` only applies to human output. Reports that
+/// link to files name them relative to `root`, the root of the repository.
+pub fn sink<W: Write + 'static>(
+    format: MessageFormat,
+    out: W,
+    color: bool,
+    group_by_rule: bool,
+    root: &Path,
+) -> Box<dyn Sink> {
+    match format {
+        MessageFormat::Human => {
+            Box::new(Human { out, color, group_by_rule, last_code: None })
+        }
+        MessageFormat::Json => Box::new(Json(out)),
+        MessageFormat::Github => Box::new(Github { out, root: root.to_owned() }),
+        MessageFormat::Sarif | MessageFormat::Checkstyle | MessageFormat::Junit => {
+            Box::new(Document { format, out, root: root.to_owned() })
+        }
+    }
+}
+
+/// Annotated snippets like rustc prints them, finished by a summary line.
+struct Human<W> {
+    out: W,
+    color: bool,
+    /// Print a header before the first diagnostic of each rule.
+    group_by_rule: bool,
+    last_code: Option<String>,
+}
+
+impl<W: Write> Sink for Human<W> {
+    fn diagnostic(
+        &mut self,
+        diag: &Diagnostic,
+        source_map: &SourceMap,
+        rules: &[RuleMeta],
+    ) -> io::Result<()> {
+        if self.group_by_rule && self.last_code.as_ref() != Some(&diag.code) {
+            let name = rules.iter().find(|r| r.code == diag.code);
+            writeln!(
+                self.out,
+                "== {}: {} ==\n",
+                diag.code,
+                name.map_or("", |r| r.name.as_str())
+            )?;
+            self.last_code = Some(diag.code.clone());
+        }
+        write!(self.out, "{}", render::render(&diag.snippet(), source_map, self.color))
+    }
+
+    fn finish(
+        &mut self,
+        diags: &[&Diagnostic],
+        _: &SourceMap,
+        _: &[RuleMeta],
+    ) -> io::Result<()> {
+        if let Some(summary) = summary(diags) {
+            writeln!(self.out, "{}", summary)?;
+        }
+        self.out.flush()
+    }
+}
+
+/// One cargo `compiler-message` per line.
+struct Json<W>(W);
+
+impl<W: Write> Sink for Json<W> {
+    fn diagnostic(
+        &mut self,
+        diag: &Diagnostic,
+        source_map: &SourceMap,
+        _: &[RuleMeta],
+    ) -> io::Result<()> {
+        writeln!(self.0, "{}", json::message(diag, source_map))
+    }
+
+    fn finish(
+        &mut self,
+        _: &[&Diagnostic],
+        _: &SourceMap,
+        _: &[RuleMeta],
+    ) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// GitHub Actions workflow commands.
+struct Github<W> {
+    out: W,
+    root: PathBuf,
+}
+
+impl<W: Write> Sink for Github<W> {
+    fn diagnostic(
+        &mut self,
+        diag: &Diagnostic,
+        source_map: &SourceMap,
+        _: &[RuleMeta],
+    ) -> io::Result<()> {
+        write!(self.out, "{}", github::annotation(diag, source_map, &self.root))
+    }
+
+    fn finish(
+        &mut self,
+        _: &[&Diagnostic],
+        _: &SourceMap,
+        _: &[RuleMeta],
+    ) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Formats that are a single document and can only be written once every diagnostic
+/// is known.
+struct Document<W> {
+    format: MessageFormat,
+    out: W,
+    root: PathBuf,
+}
+
+impl<W: Write> Sink for Document<W> {
+    fn diagnostic(
+        &mut self,
+        _: &Diagnostic,
+        _: &SourceMap,
+        _: &[RuleMeta],
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(
+        &mut self,
+        diags: &[&Diagnostic],
+        source_map: &SourceMap,
+        rules: &[RuleMeta],
+    ) -> io::Result<()> {
+        let reported =
+            diags.iter().copied().filter(|d| d.suppression.is_none()).collect::<Vec<_>>();
+        match self.format {
+            MessageFormat::Sarif => writeln!(
+                self.out,
+                "{}",
+                sarif::log(diags, source_map, rules, &self.root)
+            )?,
+            MessageFormat::Checkstyle => {
+                write!(self.out, "{}", xml::checkstyle(&reported, source_map))?
+            }
+            MessageFormat::Junit => {
+                write!(self.out, "{}", xml::junit(&reported, source_map))?
+            }
+            _ => unreachable!("not a document format"),
+        }
+        self.out.flush()
+    }
+}
+
+/// An in memory writer that can be read while a sink still holds a clone of it.
+#[derive(Clone, Debug, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
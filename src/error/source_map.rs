@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use rustc_hash::FxHashMap;
+use syntax::TextSize;
+
+/// A position in a file as editors and rustc show it.
+///
+/// Lines and columns are 1 based, the column is given in each unit a consumer might
+/// count in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: u32,
+    /// Column in bytes.
+    pub col_utf8: u32,
+    /// Column in UTF-16 code units, what LSP clients count in.
+    pub col_utf16: u32,
+    /// Column in chars, what rustc shows.
+    pub col_char: u32,
+}
+
+/// A character that takes more than one byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct WideChar {
+    /// Offset from the start of the line.
+    start: u32,
+    len_utf8: u32,
+    len_utf16: u32,
+}
+
+/// Maps byte offsets to lines and columns.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineIndex {
+    /// The offset each line starts at.
+    starts: Vec<TextSize>,
+    /// Every multi-byte character keyed by 0 based line.
+    wide: FxHashMap<u32, Vec<WideChar>>,
+    /// The length of the text, where the last line ends.
+    len: TextSize,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let mut starts = vec![TextSize::from(0)];
+        let mut wide = FxHashMap::<u32, Vec<WideChar>>::default();
+
+        let mut line_start = 0;
+        for (idx, ch) in text.char_indices() {
+            if ch == '\n' {
+                line_start = idx + 1;
+                starts.push(TextSize::from(line_start as u32));
+            } else if !ch.is_ascii() {
+                wide.entry(starts.len() as u32 - 1).or_default().push(WideChar {
+                    start: (idx - line_start) as u32,
+                    len_utf8: ch.len_utf8() as u32,
+                    len_utf16: ch.len_utf16() as u32,
+                });
+            }
+        }
+
+        Self { starts, wide, len: TextSize::of(text) }
+    }
+
+    pub fn line_count(&self) -> u32 { self.starts.len() as u32 }
+
+    /// The 1 based line and columns of `offset`.
+    pub fn position(&self, offset: TextSize) -> Position {
+        let line = match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let col = u32::from(offset - self.starts[line]);
+
+        let (mut col_utf16, mut col_char) = (col, col);
+        for c in self.wide_chars(line as u32).iter().filter(|c| c.start < col) {
+            col_utf16 -= c.len_utf8 - c.len_utf16;
+            col_char -= c.len_utf8 - 1;
+        }
+
+        Position {
+            line: line as u32 + 1,
+            col_utf8: col + 1,
+            col_utf16: col_utf16 + 1,
+            col_char: col_char + 1,
+        }
+    }
+
+    /// The offset of the 1 based `line` and `col_utf16`, `None` if the line does not
+    /// exist. Columns past the end of the line are clamped to it.
+    pub fn offset_utf16(&self, line: u32, col_utf16: u32) -> Option<TextSize> {
+        let line = line.checked_sub(1)?;
+        let start = *self.starts.get(line as usize)?;
+
+        let mut col = col_utf16.saturating_sub(1);
+        for c in self.wide_chars(line) {
+            if c.start >= col {
+                break;
+            }
+            col += c.len_utf8 - c.len_utf16;
+        }
+        let end = self.line_end(line).unwrap_or(self.len);
+        Some((start + TextSize::from(col)).min(end))
+    }
+
+    /// The offset of the newline ending the 0 based `line`, `None` for the last line.
+    fn line_end(&self, line: u32) -> Option<TextSize> {
+        self.starts.get(line as usize + 1).map(|next| *next - TextSize::from(1))
+    }
+
+    fn wide_chars(&self, line: u32) -> &[WideChar] {
+        self.wide.get(&line).map_or(&[], |w| w.as_slice())
+    }
+}
+
+/// A file's text along with its line index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceText {
+    pub text: String,
+    pub lines: LineIndex,
+}
+
+impl SourceText {
+    pub fn new(text: String) -> Self {
+        let lines = LineIndex::new(&text);
+        Self { text, lines }
+    }
+
+    /// The text of the 1 based `line` without its line ending, line 0 like whole file
+    /// diagnostics use is empty.
+    pub fn line(&self, line: u32) -> &str {
+        let line = match line.checked_sub(1) {
+            Some(line) => line,
+            None => return "",
+        };
+        let start = match self.lines.starts.get(line as usize) {
+            Some(start) => usize::from(*start),
+            None => return "",
+        };
+        let end = self.lines.line_end(line).map_or(self.text.len(), usize::from);
+        self.text[start..end].trim_end_matches('\r')
+    }
+}
+
+/// The text of every file a diagnostic points into.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: FxHashMap<String, Arc<SourceText>>,
+}
+
+impl SourceMap {
+    /// Add a file, a file that is already known keeps its text.
+    pub fn add_file(&mut self, file: &str, text: impl FnOnce() -> String) {
+        if !self.files.contains_key(file) {
+            self.files.insert(file.to_owned(), Arc::new(SourceText::new(text())));
+        }
+    }
+
+    pub fn get(&self, file: &str) -> Option<&SourceText> {
+        self.files.get(file).map(|f| &**f)
+    }
+
+    /// The position of `offset` in `file`, `None` if `file` was never added.
+    pub fn position(&self, file: &str, offset: TextSize) -> Option<Position> {
+        self.get(file).map(|f| f.lines.position(offset))
+    }
+}
+
+#[test]
+fn line_index_positions() {
+    let text = "fn a() {}\nlet é = \"𝄞\";\r\nlast";
+    let index = LineIndex::new(text);
+
+    assert_eq!(index.line_count(), 3);
+    assert_eq!(
+        index.position(TextSize::from(0)),
+        Position { line: 1, col_utf8: 1, col_utf16: 1, col_char: 1 }
+    );
+
+    let semi = TextSize::from(text.find(';').unwrap() as u32);
+    let pos = index.position(semi);
+    assert_eq!(pos, Position { line: 2, col_utf8: 16, col_utf16: 13, col_char: 12 });
+    assert_eq!(index.offset_utf16(pos.line, pos.col_utf16), Some(semi));
+
+    let last = TextSize::from(text.find("last").unwrap() as u32);
+    assert_eq!(index.position(last).line, 3);
+    assert_eq!(index.offset_utf16(3, 1), Some(last));
+    assert_eq!(index.offset_utf16(4, 1), None);
+    // A client can ask for a position past the end of the file.
+    assert_eq!(index.offset_utf16(3, 40), Some(TextSize::of(text)));
+    assert_eq!(index.offset_utf16(1, 40), Some(TextSize::from(9)));
+
+    let source = SourceText::new(text.to_owned());
+    assert_eq!(source.line(2), "let é = \"𝄞\";");
+    assert_eq!(source.line(3), "last");
+    assert_eq!(source.line(0), "");
+}
@@ -0,0 +1,163 @@
+//! Checkstyle and JUnit XML reports, the formats most CI systems know how to show.
+
+use std::fmt::Write;
+
+use super::{render, Diagnostic, Severity, SourceMap};
+
+/// A checkstyle report with a `<file>` for every file that has diagnostics.
+pub fn checkstyle(diags: &[&Diagnostic], source_map: &SourceMap) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<checkstyle version=\"4.3\">\n");
+    for (file, diags) in by_file(diags) {
+        writeln!(out, "  <file name=\"{}\">", escape(file)).unwrap();
+        for diag in diags {
+            let (line, col) = start(diag, source_map);
+            let severity = match diag.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+                Severity::Note | Severity::Help => "info",
+            };
+            writeln!(
+                out,
+                "    <error line=\"{}\" column=\"{}\" severity=\"{}\" message=\"{}\" \
+                 source=\"ruma-check.{}\"/>",
+                line,
+                col,
+                severity,
+                escape(&diag.msg),
+                escape(&diag.code)
+            )
+            .unwrap();
+        }
+        out.push_str("  </file>\n");
+    }
+    out.push_str("</checkstyle>\n");
+    out
+}
+
+/// A JUnit report with a test suite per file and a failed test case per diagnostic.
+pub fn junit(diags: &[&Diagnostic], source_map: &SourceMap) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<testsuites name=\"ruma-check\" tests=\"{0}\" failures=\"{0}\">",
+        diags.len()
+    )
+    .unwrap();
+    for (file, diags) in by_file(diags) {
+        writeln!(
+            out,
+            "  <testsuite name=\"{}\" tests=\"{1}\" failures=\"{1}\">",
+            escape(file),
+            diags.len()
+        )
+        .unwrap();
+        for diag in diags {
+            let (line, col) = start(diag, source_map);
+            writeln!(
+                out,
+                "    <testcase name=\"{} {}:{}:{}\" classname=\"{}\">",
+                escape(&diag.code),
+                escape(file),
+                line,
+                col,
+                escape(file)
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "      <failure message=\"{}\" type=\"{}\">{}</failure>",
+                escape(&diag.msg),
+                escape(&diag.code),
+                escape(&render::render(&diag.snippet(), source_map, false))
+            )
+            .unwrap();
+            out.push_str("    </testcase>\n");
+        }
+        out.push_str("  </testsuite>\n");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+/// Group diagnostics by the file they are reported in, keeping the order files are
+/// first seen in.
+fn by_file<'a>(diags: &[&'a Diagnostic]) -> Vec<(&'a str, Vec<&'a Diagnostic>)> {
+    let mut files: Vec<(&str, Vec<&Diagnostic>)> = vec![];
+    for diag in diags {
+        let file = diag.primary().map_or(diag.file.as_str(), |l| l.file.as_str());
+        match files.iter_mut().find(|(f, _)| *f == file) {
+            Some((_, diags)) => diags.push(diag),
+            None => files.push((file, vec![diag])),
+        }
+    }
+    files
+}
+
+/// The 1 based line and column a diagnostic starts at, `(0, 0)` for a whole file.
+fn start(diag: &Diagnostic, source_map: &SourceMap) -> (u32, u32) {
+    diag.primary()
+        .and_then(|l| source_map.position(&l.file, l.span.start()))
+        .map_or((0, 0), |pos| (pos.line, pos.col_char))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push(c),
+            // Other control characters are not allowed in XML 1.0 at all.
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn xml_reports() {
+    use syntax::TextRange;
+
+    use super::Label;
+
+    let mut map = SourceMap::default();
+    map.add_file("src/main.rs", || "fn main() {\n    foo!(a);\n}\n".to_owned());
+
+    let diags = vec![
+        Diagnostic::new(Severity::Error, "RC0002", "bad <macro>", "src/main.rs")
+            .with_label(Label::primary(
+                "src/main.rs",
+                TextRange::new(16.into(), 23.into()),
+                "",
+            )),
+        Diagnostic::new(Severity::Warning, "RC0001", "mod.rs & co", "src/a/mod.rs"),
+    ];
+    let diags = diags.iter().collect::<Vec<_>>();
+
+    assert_eq!(
+        checkstyle(&diags, &map),
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<checkstyle version="4.3">
+  <file name="src/main.rs">
+    <error line="2" column="5" severity="error" message="bad &lt;macro&gt;" source="ruma-check.RC0002"/>
+  </file>
+  <file name="src/a/mod.rs">
+    <error line="0" column="0" severity="warning" message="mod.rs &amp; co" source="ruma-check.RC0001"/>
+  </file>
+</checkstyle>
+"#
+    );
+
+    let junit = junit(&diags, &map);
+    assert!(junit.contains(r#"<testsuites name="ruma-check" tests="2" failures="2">"#));
+    assert!(junit
+        .contains(r#"<testcase name="RC0002 src/main.rs:2:5" classname="src/main.rs">"#));
+    assert!(junit.contains(
+        "<failure message=\"bad &lt;macro&gt;\" type=\"RC0002\">error[RC0002]"
+    ));
+}
@@ -0,0 +1,129 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    cli::Args,
+    diff,
+    error::Emitter,
+    git::{self, Worktree},
+    macro_exp::{self, ExpansionError, ExpansionLimits},
+    project::{self, CargoInfo},
+    EzError,
+};
+
+/// The expansion of every macro call keyed by file, macro name and call index.
+type Expansions = BTreeMap<(String, String, usize), Result<String, ExpansionError>>;
+
+/// Expand every macro call in the selected packages at `old` and `new` and print a
+/// unified diff for each invocation whose expansion changed.
+///
+/// Each revision is loaded as one crate graph, see `macro_exp::Workspace`, so calls to
+/// macros of other modules and crates and to proc macros expand. Calls that can't be
+/// expanded at either revision are counted in the summary instead of diffed.
+///
+/// Expansions that blow past the `args` limits are reported to the `emitter`. Returns
+/// the number of invocations that were added, removed or changed.
+pub fn expand_diff(
+    dir: &Path,
+    old: &str,
+    new: &str,
+    args: &Args,
+    emitter: &mut Emitter,
+) -> Result<usize, EzError> {
+    let repo = git::repo_root(dir)?;
+    // Check the same workspace in each worktree as the one we were run in.
+    let workspace = dir.canonicalize()?;
+    let workspace = workspace.strip_prefix(&repo).unwrap_or_else(|_| Path::new(""));
+
+    // The budget is shared by both revisions.
+    let mut limits = args.limits;
+    let before = {
+        let tree = Worktree::checkout(&repo, old)?;
+        collect(&tree.path().join(workspace), args, &mut limits, emitter)?
+    };
+    let after = {
+        let tree = Worktree::checkout(&repo, new)?;
+        collect(&tree.path().join(workspace), args, &mut limits, emitter)?
+    };
+
+    let mut changed = 0;
+    let mut unexpanded = 0;
+    for key in before.keys().chain(after.keys().filter(|k| !before.contains_key(k))) {
+        let (file, name, idx) = key;
+        match (before.get(key), after.get(key)) {
+            (Some(Err(ExpansionError::Failed)), Some(Err(ExpansionError::Failed))) => {
+                unexpanded += 1;
+                continue;
+            }
+            (Some(_), None) => println!("removed: {}: `{}!` #{}", file, name, idx),
+            (None, Some(_)) => println!("added: {}: `{}!` #{}", file, name, idx),
+            (Some(a), Some(b)) => {
+                let diff = diff::unified(
+                    &expansion_text(a),
+                    &expansion_text(b),
+                    &format!("a/{}", file),
+                    &format!("b/{}", file),
+                );
+                if diff.is_empty() {
+                    continue;
+                }
+                println!("changed: {}: `{}!` #{}", file, name, idx);
+                print!("{}", diff);
+            }
+            (None, None) => unreachable!("key came from one of the maps"),
+        }
+        changed += 1;
+    }
+
+    println!(
+        "{} of {} macro invocations changed between `{}` and `{}`",
+        changed,
+        before.len().max(after.len()),
+        old,
+        new
+    );
+    if unexpanded > 0 {
+        println!(
+            "{} invocations could not be expanded at either revision and were not \
+                 compared",
+            unexpanded
+        );
+    }
+    Ok(changed)
+}
+
+fn expansion_text(exp: &Result<String, ExpansionError>) -> String {
+    match exp {
+        Ok(text) => text.clone(),
+        Err(err) => format!("<{}>", err),
+    }
+}
+
+fn collect(
+    root: &Path,
+    args: &Args,
+    limits: &mut ExpansionLimits,
+    emitter: &mut Emitter,
+) -> Result<Expansions, EzError> {
+    let info = CargoInfo::build_crate_root(root, args)?;
+    let workspace = project::load_expander(root, args)?;
+
+    let mut expansions = Expansions::new();
+    for p in info.checked() {
+        if !args.selected(&p.name) {
+            continue;
+        }
+        for file in p.sources() {
+            let rel = file.strip_prefix(&p.dir).unwrap_or(&file);
+            let key = format!("{}/{}", p.name, rel.display());
+
+            let exps = workspace
+                .expand_file(&file, &key, limits, emitter)
+                .ok_or_else(|| format!("{:?} is not part of the workspace", file))?;
+            for exp in exps {
+                expansions.insert((key.clone(), exp.name, exp.index), exp.expanded);
+            }
+        }
+    }
+
+    Ok(expansions)
+}
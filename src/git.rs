@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::EzError;
+
+/// Run `git` in `dir` returning stdout, a non-zero exit is an error.
+pub fn git(dir: &Path, args: &[&str]) -> Result<String, EzError> {
+    let out = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run `git`: {}", e))?;
+
+    if !out.status.success() {
+        return Err(format!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+/// The top level directory of the git repository `dir` is in.
+pub fn repo_root(dir: &Path) -> Result<PathBuf, EzError> {
+    Ok(PathBuf::from(git(dir, &["rev-parse", "--show-toplevel"])?.trim()))
+}
+
+/// A revision checked out into a temporary git worktree.
+///
+/// The worktree is removed when this is dropped.
+#[derive(Debug)]
+pub struct Worktree {
+    repo: PathBuf,
+    path: PathBuf,
+}
+
+impl Worktree {
+    pub fn checkout(repo: &Path, rev: &str) -> Result<Self, EzError> {
+        let commit =
+            git(repo, &["rev-parse", "--verify", &format!("{}^{{commit}}", rev)])?;
+        // Two revisions can name the same commit so keep the directories unique.
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ruma-check-{}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst),
+            commit.trim()
+        ));
+        let path_str =
+            path.to_str().ok_or(format!("Invalid worktree path `{}`", path.display()))?;
+
+        git(repo, &["worktree", "add", "--detach", "--force", path_str, commit.trim()])?;
+
+        Ok(Self { repo: repo.to_owned(), path })
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.to_str() {
+            let _ = git(&self.repo, &["worktree", "remove", "--force", path]);
+        }
+    }
+}
+
+/// Files changed in a git repository, a check can be limited to these.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    root: PathBuf,
+    files: BTreeSet<PathBuf>,
+    /// The changes are the staged ones, files are read from the index.
+    staged: bool,
+}
+
+impl Changes {
+    /// Files in the working tree that differ from `rev`, untracked files included.
+    pub fn since(dir: &Path, rev: &str) -> Result<Self, EzError> {
+        let root = repo_root(dir)?;
+        let diff =
+            git(&root, &["diff", "--name-only", "-z", "--diff-filter=d", rev, "--"])?;
+        let untracked =
+            git(&root, &["ls-files", "-z", "--others", "--exclude-standard"])?;
+        Ok(Self::parse(&root, &(diff + &untracked)))
+    }
+
+    /// Files staged for the next commit.
+    ///
+    /// `read` gives the staged copy of each file, so unstaged edits aren't checked.
+    pub fn staged(dir: &Path) -> Result<Self, EzError> {
+        let root = repo_root(dir)?;
+        let diff =
+            git(&root, &["diff", "--name-only", "-z", "--diff-filter=d", "--cached"])?;
+        Ok(Self { staged: true, ..Self::parse(&root, &diff) })
+    }
+
+    /// Parse `git -z` output, paths relative to `root` each ended by a NUL, git doesn't
+    /// quote paths with `-z`.
+    fn parse(root: &Path, out: &str) -> Self {
+        Self {
+            root: root.to_owned(),
+            files: out
+                .split('\0')
+                .filter(|l| !l.is_empty())
+                .map(|l| root.join(l))
+                .collect(),
+            staged: false,
+        }
+    }
+
+    /// The text of `file` that is checked, the staged blob for staged changes and the
+    /// working tree copy otherwise.
+    pub fn read(&self, file: &Path) -> Result<String, EzError> {
+        match file.strip_prefix(&self.root) {
+            Ok(rel) if self.staged && self.contains(file) => {
+                let rel = rel.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>();
+                git(&self.root, &["show", &format!(":{}", rel.join("/"))])
+            }
+            _ => Ok(fs::read_to_string(file)
+                .map_err(|_| format!("Failed to open file at {:?}", file))?),
+        }
+    }
+
+    pub fn contains(&self, file: &Path) -> bool { self.files.contains(file) }
+
+    /// Is any changed file in `dir`.
+    pub fn touches(&self, dir: &Path) -> bool {
+        self.files.iter().any(|f| f.starts_with(dir))
+    }
+}
+
+const HOOK: &str = "#!/bin/sh\n# Installed by `ruma-check install-hook`.\n";
+
+/// Install a pre-commit hook running ruma-check on the staged files of the repository
+/// `dir` is in, returns where the hook was written.
+///
+/// A hook we didn't write is never replaced.
+pub fn install_hook(dir: &Path) -> Result<PathBuf, EzError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Respects `core.hooksPath` and works from inside a worktree.
+    let hooks = PathBuf::from(git(dir, &["rev-parse", "--git-path", "hooks"])?.trim());
+    let hooks = if hooks.is_absolute() { hooks } else { dir.join(hooks) };
+    let hook = hooks.join("pre-commit");
+    if let Ok(existing) = fs::read_to_string(&hook) {
+        if !existing.starts_with(HOOK) {
+            return Err(format!(
+                "A pre-commit hook not written by ruma-check exists at `{}`",
+                hook.display()
+            )
+            .into());
+        }
+    }
+
+    let exe = std::env::current_exe()?;
+    fs::create_dir_all(&hooks)?;
+    fs::write(
+        &hook,
+        format!("{}exec {} --staged\n", HOOK, shell_quote(&exe.display().to_string())),
+    )
+    .map_err(|e| format!("Failed to write `{}`: {}", hook.display(), e))?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    Ok(hook)
+}
+
+/// `text` quoted for `sh`, a `'` can't be escaped inside single quotes so it ends the
+/// quoted part.
+fn shell_quote(text: &str) -> String { format!("'{}'", text.replace('\'', "'\\''")) }
+
+#[test]
+fn changed_files() {
+    let changes = Changes::parse(
+        Path::new("/ws"),
+        "ruma/src/lib.rs\0ruma-common/build.rs\0ruma/src/\u{e9}t\u{e9}.rs\0",
+    );
+
+    assert!(changes.contains(Path::new("/ws/ruma/src/lib.rs")));
+    assert!(!changes.contains(Path::new("/ws/ruma/src/api.rs")));
+    assert!(changes.touches(Path::new("/ws/ruma-common")));
+    assert!(!changes.touches(Path::new("/ws/ruma-events")));
+    assert!(changes.contains(Path::new("/ws/ruma/src/été.rs")));
+}
+
+#[test]
+fn quoted_for_sh() {
+    assert_eq!(shell_quote("/usr/bin/ruma-check"), "'/usr/bin/ruma-check'");
+    assert_eq!(shell_quote("/home/o'neil/ruma-check"), "'/home/o'\\''neil/ruma-check'");
+}
+
+#[test]
+fn staged_blob() {
+    let repo =
+        std::env::temp_dir().join(format!("ruma-check-staged-{}", std::process::id()));
+    fs::create_dir_all(&repo).unwrap();
+    let run = |args: &[&str]| git(&repo, args).map(drop);
+    let changes = run(&["init", "-q"])
+        .and_then(|_| fs::write(repo.join("lib.rs"), "staged").map_err(Into::into))
+        .and_then(|_| run(&["add", "lib.rs"]))
+        .and_then(|_| fs::write(repo.join("lib.rs"), "unstaged").map_err(Into::into))
+        .and_then(|_| Changes::staged(&repo))
+        .and_then(|changes| {
+            let lib = changes.root.join("lib.rs");
+            Ok((changes.contains(&lib), changes.read(&lib)?))
+        });
+    fs::remove_dir_all(&repo).unwrap();
+
+    assert_eq!(changes.unwrap(), (true, "staged".to_owned()));
+}
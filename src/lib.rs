@@ -0,0 +1,65 @@
+//! The rules, diagnostics and macro expansion behind `ruma-check`.
+//!
+//! Custom drivers check files through a `rules::Registry`, registering their own node
+//! and token rules, declared with `declare_rule!`, next to the built in ones and report
+//! with an `Emitter`:
+//!
+//! ```no_run
+//! use ruma_check::{error::Emitter, rules::Registry};
+//!
+//! let registry = Registry::default();
+//! let mut emitter = Emitter::default();
+//! // Reports describe the rules of the registry.
+//! emitter.set_rules(registry.meta());
+//! registry.validate_source(&"src/lib.rs", "fn main() {}", &mut emitter).unwrap();
+//! emitter.emit().unwrap();
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub mod baseline;
+pub mod cfg_eval;
+pub mod error;
+pub mod macro_exp;
+pub mod rules;
+pub mod timings;
+
+pub use error::{Diagnostic, Emitter};
+pub use macro_exp::MacroExpander;
+pub use rules::{validate_source, NodeRule, Registry, Rule, TokenRule};
+
+pub type EzError = Box<dyn std::error::Error>;
+
+/// Every file under `dir`, in sorted order.
+pub fn walk_dirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    Walker { stack: vec![dir.to_owned()] }
+}
+
+struct Walker {
+    /// Paths still to visit, the next one last.
+    stack: Vec<PathBuf>,
+}
+
+impl Iterator for Walker {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let path = self.stack.pop()?;
+            if path.is_file() {
+                return Some(path);
+            }
+            if let Ok(entries) = fs::read_dir(&path) {
+                let mut entries =
+                    entries.filter_map(|e| e.ok().map(|e| e.path())).collect::<Vec<_>>();
+                // Reversed so paths are popped in sorted order whatever order the
+                // filesystem lists them in, a directory is done before the next entry.
+                entries.sort_by(|a, b| b.cmp(a));
+                self.stack.extend(entries);
+            }
+        }
+    }
+}
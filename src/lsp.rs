@@ -0,0 +1,487 @@
+//! `ruma-check lsp`, a language server over stdio publishing our diagnostics.
+//!
+//! Only the small part of the protocol we need is implemented: full document sync,
+//! diagnostics on open, change and save, quick fixes from rule suggestions and the
+//! `ruma-check.expandMacro` command.
+//!
+//! The workspace at the client's root is loaded on `initialize`, documents are checked
+//! with the cfgs and edition of their package like `check` does.
+
+use std::{
+    io::{self, BufRead, Read, Write},
+    path::PathBuf,
+};
+
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+use syntax::{algo::find_node_at_offset, ast, AstNode, TextRange, TextSize};
+
+use crate::{
+    cfg_eval::CfgOptions,
+    cfg_sets,
+    cli::Args,
+    error::{Applicability, Diagnostic, Emitter, LineIndex, Severity},
+    macro_exp::{self, Edition, ExpansionLimits},
+    project::{CargoInfo, Package},
+    rules, EzError,
+};
+
+const EXPAND_MACRO: &str = "ruma-check.expandMacro";
+
+/// The JSON-RPC error code for an unknown method.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// The JSON-RPC error code for bad parameters.
+const INVALID_PARAMS: i64 = -32602;
+
+/// Serve the language server protocol on stdin and stdout until the client exits,
+/// `--features` and `--target` in `args` apply to every document.
+pub fn run(args: &Args) -> Result<(), EzError> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let (mut input, mut output) = (stdin.lock(), stdout.lock());
+
+    let mut server = Server { args: args.clone(), ..Server::default() };
+    while let Some(msg) = read_message(&mut input)? {
+        for reply in server.handle(msg) {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>, EzError> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            len = Some(value.trim().parse::<usize>()?);
+        }
+    }
+
+    let mut body = vec![0; len.ok_or("Message without a `Content-Length` header")?];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(output: &mut impl Write, msg: &Value) -> Result<(), EzError> {
+    let body = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn response(id: &Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error(id: &Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+#[derive(Debug, Default)]
+struct Server {
+    /// The text of every open document keyed by URI.
+    docs: FxHashMap<String, String>,
+    args: Args,
+    /// The packages of the workspace at the client's root.
+    packages: Vec<Package>,
+    exit: bool,
+}
+
+impl Server {
+    /// Handle one message returning the responses and notifications to send.
+    fn handle(&mut self, msg: Value) -> Vec<Value> {
+        let method = msg["method"].as_str().unwrap_or_default();
+        let params = &msg["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_owned();
+
+        let id = match msg.get("id") {
+            Some(id) => id,
+            // A notification, there is nothing to respond to.
+            None => {
+                return match method {
+                    "textDocument/didOpen" => {
+                        let text = params["textDocument"]["text"].as_str();
+                        self.docs
+                            .insert(uri.clone(), text.unwrap_or_default().to_owned());
+                        vec![self.publish(&uri)]
+                    }
+                    "textDocument/didChange" => {
+                        // We ask for full sync so the last change is the whole document.
+                        let changes = params["contentChanges"].as_array();
+                        if let Some(text) = changes
+                            .and_then(|c| c.last())
+                            .and_then(|c| c["text"].as_str())
+                        {
+                            self.docs.insert(uri.clone(), text.to_owned());
+                        }
+                        vec![self.publish(&uri)]
+                    }
+                    "textDocument/didSave" => {
+                        if let Some(text) = params["text"].as_str() {
+                            self.docs.insert(uri.clone(), text.to_owned());
+                        }
+                        vec![self.publish(&uri)]
+                    }
+                    "textDocument/didClose" => {
+                        self.docs.remove(&uri);
+                        vec![notification(
+                            "textDocument/publishDiagnostics",
+                            json!({ "uri": uri, "diagnostics": [] }),
+                        )]
+                    }
+                    "exit" => {
+                        self.exit = true;
+                        vec![]
+                    }
+                    _ => vec![],
+                };
+            }
+        };
+
+        let reply = match method {
+            "initialize" => {
+                self.load_workspace(params);
+                response(
+                    id,
+                    json!({
+                        "capabilities": {
+                            "textDocumentSync": {
+                                "openClose": true,
+                                // Full
+                                "change": 1,
+                                "save": { "includeText": true },
+                            },
+                            "codeActionProvider": true,
+                            "executeCommandProvider": { "commands": [EXPAND_MACRO] },
+                        },
+                        "serverInfo": { "name": "ruma-check" },
+                    }),
+                )
+            }
+            "shutdown" => response(id, Value::Null),
+            "textDocument/codeAction" => response(id, self.code_actions(&uri, params)),
+            "workspace/executeCommand" if params["command"] == EXPAND_MACRO => {
+                match self.expand_macro(&params["arguments"]) {
+                    Ok(expansion) => response(id, Value::String(expansion)),
+                    Err(e) => error(id, INVALID_PARAMS, &e),
+                }
+            }
+            _ => error(id, METHOD_NOT_FOUND, &format!("Unknown method `{}`", method)),
+        };
+        vec![reply]
+    }
+
+    /// Load the packages of the workspace at the client's root, a workspace that fails
+    /// to load leaves every document outside of any package.
+    fn load_workspace(&mut self, params: &Value) {
+        let root = params["rootUri"]
+            .as_str()
+            .map(uri_to_path)
+            .or_else(|| params["rootPath"].as_str().map(PathBuf::from));
+        if let Some(root) = root {
+            match CargoInfo::build_crate_root(&root, &self.args) {
+                Ok(info) => self.packages = info.checked().cloned().collect(),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+
+    /// The cfg sets and edition of the innermost package containing `uri`.
+    fn settings(&self, uri: &str) -> (Vec<CfgOptions>, Edition) {
+        let path = uri_to_path(uri);
+        self.packages
+            .iter()
+            .filter(|p| path.starts_with(&p.dir))
+            .max_by_key(|p| p.dir.components().count())
+            .map_or_else(
+                // Outside of the workspace, assume a recent crate.
+                || (vec![CfgOptions::default()], Edition::Edition2018),
+                |p| (cfg_sets(p, &self.args), p.edition),
+            )
+    }
+
+    /// Check the document at `uri` and build the `publishDiagnostics` notification.
+    fn publish(&self, uri: &str) -> Value {
+        let text = self.docs.get(uri).map_or("", |t| t.as_str());
+        let lines = LineIndex::new(text);
+        let diagnostics = check(uri, text, &self.settings(uri).0)
+            .iter()
+            .map(|diag| {
+                // We only have the text of this document so spans into other files
+                // can't be converted.
+                let local = |file: &str| file == diag.file;
+                let label_range = |span: TextRange| range(&lines, span.start(), span.end());
+
+                let primary = diag.primary().filter(|l| local(&l.file));
+                let related = diag
+                    .labels
+                    .iter()
+                    .filter(|l| !l.primary && local(&l.file))
+                    .map(|l| {
+                        json!({
+                            "location": { "uri": uri, "range": label_range(l.span) },
+                            "message": l.msg,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let suggestions = diag
+                    .suggestions
+                    .iter()
+                    .map(|s| {
+                        let edits = s
+                            .edits
+                            .iter()
+                            .filter(|e| local(&e.file))
+                            .map(|e| {
+                                json!({ "range": label_range(e.span), "newText": e.replacement })
+                            })
+                            .collect::<Vec<_>>();
+                        json!({
+                            "title": s.msg,
+                            "edits": edits,
+                            "preferred": s.applicability == Applicability::MachineApplicable,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut message = diag.msg.clone();
+                for note in &diag.notes {
+                    message.push_str("\nnote: ");
+                    message.push_str(note);
+                }
+
+                json!({
+                    "range": primary.map_or_else(
+                        || range(&lines, TextSize::from(0), TextSize::from(0)),
+                        |l| label_range(l.span),
+                    ),
+                    "severity": match diag.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                        Severity::Note => 3,
+                        Severity::Help => 4,
+                    },
+                    "code": diag.code,
+                    "source": "ruma-check",
+                    "message": message,
+                    "relatedInformation": related,
+                    "data": { "suggestions": suggestions },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        notification(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+
+    fn code_actions(&self, uri: &str, params: &Value) -> Value {
+        let mut actions = vec![];
+
+        let empty = vec![];
+        let diagnostics = params["context"]["diagnostics"].as_array().unwrap_or(&empty);
+        for diag in diagnostics.iter().filter(|d| d["source"] == "ruma-check") {
+            let suggestions = diag["data"]["suggestions"].as_array().unwrap_or(&empty);
+            // A suggestion without edits is only advice, there is nothing to apply.
+            let fixes = suggestions
+                .iter()
+                .filter(|sugg| sugg["edits"].as_array().map_or(false, |e| !e.is_empty()));
+            for sugg in fixes {
+                actions.push(json!({
+                    "title": sugg["title"],
+                    "kind": "quickfix",
+                    "diagnostics": [diag],
+                    "isPreferred": sugg["preferred"],
+                    "edit": { "changes": { uri: sugg["edits"] } },
+                }));
+            }
+        }
+
+        let text = self.docs.get(uri).map_or("", |t| t.as_str());
+        let start = &params["range"]["start"];
+        if let Some(offset) = offset(&LineIndex::new(text), start) {
+            let source = rules::parse(text);
+            if find_node_at_offset::<ast::MacroCall>(source.tree().syntax(), offset)
+                .is_some()
+            {
+                actions.push(json!({
+                    "title": "Expand macro (ruma-check)",
+                    "kind": "refactor",
+                    "command": {
+                        "title": "Expand macro (ruma-check)",
+                        "command": EXPAND_MACRO,
+                        "arguments": [uri, start],
+                    },
+                }));
+            }
+        }
+
+        Value::Array(actions)
+    }
+
+    /// Expand the macro call at the `[uri, position]` given as the command arguments.
+    fn expand_macro(&self, args: &Value) -> Result<String, String> {
+        let uri = args[0].as_str().ok_or("Expected a document URI")?;
+        let text = self.docs.get(uri).ok_or(format!("`{}` is not open", uri))?;
+        let offset = offset(&LineIndex::new(text), &args[1])
+            .ok_or("Expected a position in the document")?;
+
+        let (cfgs, edition) = self.settings(uri);
+        // Expanding is slow, like `expand-diff` only the first feature set is expanded.
+        let cfg = cfgs.into_iter().next().unwrap_or_default();
+        let path = uri_to_path(uri).display().to_string();
+        let mut limits = ExpansionLimits::default();
+        macro_exp::expand_at(&path, text, offset, &cfg, edition, &mut limits)
+            .ok_or("No macro call at this position")?
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Run every rule over `text` as if it were the file at `uri`, skipping code every one
+/// of `cfgs` turns off.
+fn check(uri: &str, text: &str, cfgs: &[CfgOptions]) -> Vec<Diagnostic> {
+    let mut emitter = Emitter::default();
+    match rules::validate_parsed(
+        &uri_to_path(uri),
+        &rules::parse(text),
+        cfgs,
+        &mut emitter,
+    ) {
+        Ok(()) => emitter.reported().cloned().collect(),
+        Err(e) => {
+            eprintln!("{}", e);
+            vec![]
+        }
+    }
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+
+    let mut bytes = vec![];
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = iter.next().into_iter().chain(iter.next()).collect::<Vec<_>>();
+            if let Some(b) = std::str::from_utf8(&hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                bytes.push(b);
+                continue;
+            }
+            bytes.push(b'%');
+            bytes.extend(hex);
+        } else {
+            bytes.push(b);
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// An LSP position is a 0 based line and a character offset in UTF-16 code units.
+fn position(lines: &LineIndex, offset: TextSize) -> Value {
+    let pos = lines.position(offset);
+    json!({ "line": pos.line - 1, "character": pos.col_utf16 - 1 })
+}
+
+fn range(lines: &LineIndex, start: TextSize, end: TextSize) -> Value {
+    json!({ "start": position(lines, start), "end": position(lines, end) })
+}
+
+/// The inverse of `position`.
+fn offset(lines: &LineIndex, position: &Value) -> Option<TextSize> {
+    let line = position["line"].as_u64()? as u32;
+    let character = position["character"].as_u64()? as u32;
+    lines.offset_utf16(line + 1, character + 1)
+}
+
+#[test]
+fn positions_are_utf16() {
+    let text = "fn a() {}\nlet é = \"𝄞\";\n";
+    let offset_of_semi = TextSize::from(text.find(';').unwrap() as u32);
+
+    let lines = LineIndex::new(text);
+
+    let pos = position(&lines, offset_of_semi);
+    assert_eq!(pos, json!({ "line": 1, "character": 12 }));
+    assert_eq!(offset(&lines, &pos), Some(offset_of_semi));
+    let past_eof = json!({ "line": 2, "character": 10 });
+    assert_eq!(offset(&lines, &past_eof), Some(TextSize::of(text)));
+}
+
+#[test]
+fn open_publishes_diagnostics() {
+    let mut server = Server::default();
+    let replies = server.handle(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": { "textDocument": { "uri": "file:///ruma/src/mod.rs", "text": "" } },
+    }));
+
+    assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+    assert_eq!(replies[0]["params"]["diagnostics"].as_array().unwrap().len(), 1);
+
+    // The suggestion of `mod.rs` has no edits, so it isn't offered as a quick fix.
+    let replies = server.handle(json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "textDocument/codeAction",
+        "params": {
+            "textDocument": { "uri": "file:///ruma/src/mod.rs" },
+            "range": replies[0]["params"]["diagnostics"][0]["range"],
+            "context": { "diagnostics": replies[0]["params"]["diagnostics"] },
+        },
+    }));
+    assert_eq!(replies[0]["result"], json!([]));
+}
+
+#[test]
+fn documents_use_their_package() {
+    let root =
+        std::env::temp_dir().join(format!("ruma-check-lsp-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[package]\nname = \"app\"\n\n[features]\nunstable = []\n",
+    )
+    .unwrap();
+
+    let mut server = Server::default();
+    server.args.features.features = vec!["unstable".to_owned()];
+    server.packages =
+        CargoInfo::from_manifests(&root.join("Cargo.toml")).unwrap().packages;
+    let text = "#[cfg(not(feature = \"unstable\"))]\nfn a() {\n    foo!(a);\n}\n";
+    let mut open = |uri: &str| {
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "text": text } },
+        }));
+        replies[0]["params"]["diagnostics"].as_array().unwrap().len()
+    };
+
+    // `unstable` is enabled for the package, outside of it features are unknown.
+    assert_eq!(open(&format!("file://{}/src/lib.rs", root.display())), 0);
+    assert_eq!(open("file:///elsewhere/src/lib.rs"), 1);
+    let uri = format!("file://{}/src/lib.rs", root.display());
+    assert_eq!(server.settings(&uri).1, Edition::Edition2015);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -1,8 +1,16 @@
 #![allow(unused)]
 
 use std::{
-    fmt, iter,
-    sync::{Arc, Mutex},
+    convert::TryFrom,
+    env, fmt, fs, iter, panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use base_db::{
@@ -16,12 +24,29 @@ use hir_def::db::DefDatabase;
 use hir_expand::db::AstDatabase;
 use hir_ty::db::HirDatabase;
 use ide_db::symbol_index::{self, SymbolsDatabase};
-use rustc_hash::FxHashSet;
+use paths::AbsPathBuf;
+use proc_macro_api::ProcMacroClient;
+use project_model::{CargoConfig, ProjectManifest, ProjectWorkspace};
+use rustc_hash::{FxHashMap, FxHashSet};
 use syntax::{
-    ast, ast::MacroCall, ted, AstNode, NodeOrToken, SyntaxKind, SyntaxKind::*,
-    SyntaxNode, WalkEvent, T,
+    algo::{find_node_at_offset, find_node_at_range},
+    ast,
+    ast::MacroCall,
+    ted, AstNode, NodeOrToken, SyntaxKind,
+    SyntaxKind::*,
+    SyntaxNode, TextRange, TextSize, WalkEvent, T,
 };
 
+use crate::{
+    cfg_eval::CfgOptions,
+    error::Emitter,
+    timings::{self, Phase},
+    walk_dirs, EzError,
+};
+
+/// The argument that runs `ruma-check` as the proc macro server of a `Workspace`.
+pub const PROC_MACRO_SERVER: &str = "proc-macro";
+
 #[salsa::database(
     base_db::SourceDatabaseExtStorage,
     base_db::SourceDatabaseStorage,
@@ -34,9 +59,14 @@ use syntax::{
 #[derive(Default)]
 pub struct MacroExpander {
     storage: salsa::Storage<MacroExpander>,
-    events: Mutex<Option<Vec<salsa::Event>>>,
+    events: Mutex<Option<Vec<(Instant, salsa::Event)>>>,
+    /// Set when the expansion running on this database was given up on.
+    cancelled: Arc<AtomicBool>,
 }
 
+/// The panic payload that unwinds a cancelled expansion.
+struct Cancelled;
+
 impl fmt::Debug for MacroExpander {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("TestDB").finish()
@@ -57,9 +87,14 @@ impl Upcast<dyn HirDatabase> for MacroExpander {
 
 impl salsa::Database for MacroExpander {
     fn salsa_event(&self, event: salsa::Event) {
+        // Every query goes through here, so an expansion that was given up on stops at
+        // the next query it runs instead of running on in the background.
+        if self.cancelled.load(Ordering::Relaxed) {
+            panic::resume_unwind(Box::new(Cancelled));
+        }
         let mut events = self.events.lock().unwrap();
         if let Some(events) = &mut *events {
-            events.push(event);
+            events.push((Instant::now(), event));
         }
     }
 }
@@ -68,7 +103,9 @@ impl salsa::ParallelDatabase for MacroExpander {
     fn snapshot(&self) -> salsa::Snapshot<MacroExpander> {
         salsa::Snapshot::new(MacroExpander {
             storage: self.storage.snapshot(),
-            events: Default::default(),
+            // Keep recording if we are being timed so the snapshot's queries are counted.
+            events: Mutex::new(self.events.lock().unwrap().as_ref().map(|_| vec![])),
+            cancelled: Arc::default(),
         })
     }
 }
@@ -86,58 +123,57 @@ impl FileLoader for MacroExpander {
 }
 
 impl MacroExpander {
+    /// A database with nothing loaded, recording salsa events when timings are enabled.
+    fn new() -> Self {
+        let db = MacroExpander::default();
+        if timings::is_enabled() {
+            *db.events.lock().unwrap() = Some(vec![]);
+        }
+        db
+    }
+
     /// Parse "files" into a `TypeResolver` that can walk the items in a crate.
     ///
-    /// The crate root must be the first file.
-    pub fn parse_crate(inputs: Vec<&str>) -> (Self, Vec<FileId>) {
-        let mut ids = vec![];
-        let mut db = MacroExpander::default();
+    /// Each input is the path of a file and its text, the crate root must be the first
+    /// file. Modules are resolved by path, relative paths are put under a virtual `/`.
+    /// `cfg` and `edition` are what the crate is compiled with.
+    pub fn parse_crate(
+        inputs: Vec<(&str, &str)>,
+        cfg: &CfgOptions,
+        edition: Edition,
+    ) -> (Self, Vec<FileId>) {
+        let _t = timings::phase(Phase::CrateGraph);
+        let mut db = MacroExpander::new();
 
         let mut files = FileSet::default();
-        for i in (0..inputs.len()).map(|i| FileId(i as u32)) {
-            ids.push(i);
-            files.insert(i, VfsPath::new_virtual_path("/test".to_string()));
+        let ids = (0..inputs.len()).map(|i| FileId(i as u32)).collect::<Vec<_>>();
+        for (&id, (path, _)) in ids.iter().zip(&inputs) {
+            files.insert(id, vfs_path(path));
         }
 
-        let root = SourceRoot::new_library(files);
         // Since we will never change the DB set high durability.
         let durability = Durability::HIGH;
-
-        // TODO: loop over crates when we do that...
-        let lib_roots = root
-            .iter()
-            .enumerate()
-            .map(|(i, _)| SourceRootId(i as u32))
-            .collect::<FxHashSet<_>>();
-
-        db.set_library_roots_with_durability(Arc::new(lib_roots), durability);
-
-        for (i, file_id) in root.iter().enumerate() {
-            db.set_file_source_root_with_durability(
-                file_id,
-                SourceRootId(i as u32),
-                durability,
-            );
+        let root_id = SourceRootId(0);
+        let root = SourceRoot::new_local(files);
+        for &id in &ids {
+            db.set_file_source_root_with_durability(id, root_id, durability);
         }
-
-        for (file_id, text) in
-            inputs.iter().enumerate().map(|(i, t)| (FileId(i as u32), t))
-        {
-            let _source_root_id = db.file_source_root(file_id);
-            // XXX: can't actually remove the file, just reset the text
-            db.set_file_text_with_durability(
-                file_id,
-                Arc::new(text.to_string()),
-                durability,
-            )
+        for (&id, (_, text)) in ids.iter().zip(&inputs) {
+            db.set_file_text_with_durability(id, Arc::new(text.to_string()), durability);
         }
+        db.set_source_root_with_durability(root_id, Arc::new(root), durability);
+        db.set_local_roots_with_durability(
+            Arc::new(iter::once(root_id).collect()),
+            durability,
+        );
+        db.set_library_roots_with_durability(Arc::default(), durability);
 
         let mut crate_graph = CrateGraph::default();
         crate_graph.add_crate_root(
             ids[0],
-            base_db::Edition::Edition2018,
+            edition.to_ra(),
             Some(CrateDisplayName::from_canonical_name("test".to_string())),
-            Default::default(),
+            cfg.to_ra(),
             Env::default(),
             vec![],
         );
@@ -146,11 +182,52 @@ impl MacroExpander {
 
         (db, ids)
     }
+
+    /// Drain the recorded salsa events into each executed query and roughly how long
+    /// it took.
+    ///
+    /// Salsa only reports when a query starts executing, not when it finishes, so each
+    /// query is timed until the next event. A query that runs other queries is only
+    /// timed until the first of them starts, time spent after a query returns is
+    /// counted for the query that started last.
+    fn take_query_times(&self) -> Vec<(String, Duration)> {
+        let events = match self.events.lock().unwrap().as_mut() {
+            Some(events) => std::mem::take(events),
+            None => return vec![],
+        };
+
+        let end = Instant::now();
+        events
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (start, event))| match &event.kind {
+                salsa::EventKind::WillExecute { database_key } => {
+                    let name = format!("{:?}", database_key.debug(self));
+                    let name = name[..name.find('(').unwrap_or(name.len())].to_owned();
+                    let next = events.get(i + 1).map_or(end, |(next, _)| *next);
+                    Some((name, next - *start))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The path of `path` in the database, relative paths are put under a virtual `/`.
+fn vfs_path(path: &str) -> VfsPath {
+    match AbsPathBuf::try_from(PathBuf::from(path)) {
+        Ok(abs) => VfsPath::from(abs),
+        Err(_) => VfsPath::new_virtual_path(format!("/{}", path)),
+    }
 }
 
 fn is_text(k: SyntaxKind) -> bool { k.is_keyword() || k.is_literal() || k == IDENT }
 
-fn insert_whitespaces(syn: SyntaxNode) -> String {
+/// Format an expansion, giving up as soon as the output grows past `max_output` bytes.
+fn insert_whitespaces(
+    syn: SyntaxNode,
+    max_output: usize,
+) -> Result<String, ExpansionError> {
     let mut res = String::new();
     let mut token_iter = syn
         .preorder_with_tokens()
@@ -167,6 +244,9 @@ fn insert_whitespaces(syn: SyntaxNode) -> String {
     let mut last: Option<SyntaxKind> = None;
 
     while let Some(token) = token_iter.next() {
+        if res.len() > max_output {
+            return Err(ExpansionError::TooLarge(res.len()));
+        }
         let mut is_next = |f: fn(SyntaxKind) -> bool, default| -> bool {
             token_iter.peek().map(|it| f(it.kind())).unwrap_or(default)
         };
@@ -217,7 +297,10 @@ fn insert_whitespaces(syn: SyntaxNode) -> String {
 
         last = Some(token.kind());
     }
-    res
+    if res.len() > max_output {
+        return Err(ExpansionError::TooLarge(res.len()));
+    }
+    Ok(res)
 }
 
 pub fn expand_macros<Db: HirDatabase>(
@@ -227,12 +310,432 @@ pub fn expand_macros<Db: HirDatabase>(
     db.expand(mac)
 }
 
+/// The Rust edition a crate is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edition {
+    Edition2015,
+    Edition2018,
+    Edition2021,
+}
+
+impl Default for Edition {
+    /// What cargo uses when a manifest doesn't say.
+    fn default() -> Self { Self::Edition2015 }
+}
+
+impl Edition {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Edition2015 => "2015",
+            Self::Edition2018 => "2018",
+            Self::Edition2021 => "2021",
+        }
+    }
+
+    pub fn parse(edition: &str) -> Option<Self> {
+        Some(match edition {
+            "2015" => Self::Edition2015,
+            "2018" => Self::Edition2018,
+            "2021" => Self::Edition2021,
+            _ => return None,
+        })
+    }
+
+    /// The edition `cargo metadata` gave rust-analyzer.
+    ///
+    /// The pinned rust-analyzer has no 2021 edition and loads a 2021 crate as 2018, so
+    /// a crate loaded through cargo is never `Edition2021`.
+    pub fn from_ra(edition: base_db::Edition) -> Self {
+        match edition {
+            base_db::Edition::Edition2015 => Self::Edition2015,
+            base_db::Edition::Edition2018 => Self::Edition2018,
+        }
+    }
+
+    /// The edition rust-analyzer resolves the crate with.
+    pub fn to_ra(self) -> base_db::Edition {
+        match self {
+            Self::Edition2015 => base_db::Edition::Edition2015,
+            // rust-analyzer doesn't know 2021 yet, paths resolve like they do in 2018.
+            Self::Edition2018 | Self::Edition2021 => base_db::Edition::Edition2018,
+        }
+    }
+}
+
+/// The code of the diagnostic emitted when an expansion exceeds its limits.
+pub const EXPANSION_STOPPED: &str = "RC0100";
+
+/// Limits placed on macro expansion so a misbehaving macro cannot hang a run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExpansionLimits {
+    /// The longest a single expansion may take.
+    pub timeout: Duration,
+    /// The largest an expansion's output may be, in bytes.
+    pub max_output: usize,
+    /// The time left for all remaining expansions in this run.
+    pub budget: Duration,
+}
+
+impl Default for ExpansionLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_output: 1024 * 1024,
+            budget: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Why a macro call has no expansion.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpansionError {
+    /// rust-analyzer could not expand the macro.
+    Failed,
+    /// The expansion took longer than the per expansion timeout.
+    TimedOut(Duration),
+    /// The output was larger than allowed, holds the size of the output in bytes.
+    TooLarge(usize),
+    /// The budget for the whole run ran out.
+    OverBudget,
+}
+
+impl fmt::Display for ExpansionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpansionError::Failed => write!(f, "failed to expand"),
+            ExpansionError::TimedOut(after) => write!(f, "timed out after {:?}", after),
+            ExpansionError::TooLarge(size) => write!(f, "expanded to {} bytes", size),
+            ExpansionError::OverBudget => write!(f, "the expansion time budget ran out"),
+        }
+    }
+}
+
+/// A macro invocation found in a file and the code it expands to.
+#[derive(Clone, Debug)]
+pub struct Expansion {
+    /// The path of the macro as written at the call site.
+    pub name: String,
+    /// The number of calls to a macro with the same name before this one in the file.
+    pub index: usize,
+    pub range: TextRange,
+    /// The formatted expansion.
+    pub expanded: Result<String, ExpansionError>,
+}
+
+/// Expand every macro invocation in the given file.
+///
+/// The file is expanded as a crate of its own, so only calls to macros defined in the
+/// file itself, before the call, expand. Use a `Workspace` to expand calls to macros of
+/// other modules and crates and to proc macros.
+///
+/// Any expansion that fails to finish within `limits` is reported to the `emitter`.
+pub fn expand_file(
+    path: &str,
+    text: &str,
+    cfg: &CfgOptions,
+    edition: Edition,
+    limits: &mut ExpansionLimits,
+    emitter: &mut Emitter,
+) -> Vec<Expansion> {
+    let (db, ids) = MacroExpander::parse_crate(vec![(path, text)], cfg, edition);
+    expand_calls(&db, ids[0], path, limits, emitter)
+}
+
+/// Expand every macro invocation in `file_id`, reported to the `emitter` as `path`.
+fn expand_calls(
+    db: &MacroExpander,
+    file_id: FileId,
+    path: &str,
+    limits: &mut ExpansionLimits,
+    emitter: &mut Emitter,
+) -> Vec<Expansion> {
+    let file = Semantics::new(db).parse(file_id);
+    let mut seen = FxHashMap::<String, usize>::default();
+    let mut expansions = vec![];
+    for mac in file.syntax().descendants().filter_map(MacroCall::cast) {
+        let name = mac.path().map_or_else(String::new, |p| p.syntax().to_string());
+        let index = seen.entry(name.clone()).or_default();
+        *index += 1;
+
+        let range = mac.syntax().text_range();
+        let expanded = {
+            let _t = timings::phase(Phase::Expansion);
+            expand_limited(db, file_id, range, limits)
+        };
+
+        match &expanded {
+            // Macros rust-analyzer can't expand are not a problem with the macro.
+            Ok(_) | Err(ExpansionError::Failed) => {}
+            Err(err) => emitter.sugg_with_span(
+                EXPANSION_STOPPED,
+                &format!("Expansion of `{}!` was stopped: {}", name, err),
+                "check the macro for unbounded recursion or repetition, or raise the \
+                limit with `--expand-timeout`, `--expand-max-bytes` or `--expand-budget`",
+                range,
+                mac.syntax().clone(),
+                path,
+            ),
+        }
+
+        expansions.push(Expansion { name, index: *index - 1, range, expanded });
+    }
+
+    timings::queries(db.take_query_times());
+    expansions
+}
+
+/// A cargo workspace or `rust-project.json` loaded for expansion, every file of every
+/// crate along with its dependencies in one crate graph.
+///
+/// Proc macros are expanded by this executable run as `ruma-check proc-macro`, like
+/// rust-analyzer does. Cargo has to build them first, so loading runs `cargo check`
+/// when the `CargoConfig` asks for `load_out_dirs_from_check`.
+pub struct Workspace {
+    db: MacroExpander,
+    /// The id of every loaded file.
+    files: FxHashMap<PathBuf, FileId>,
+    /// Keeps the proc macro server running while the workspace is loaded.
+    _proc_macros: ProcMacroClient,
+}
+
+impl Workspace {
+    /// Load the project `manifest`, a `Cargo.toml` or `rust-project.json`, with `config`.
+    ///
+    /// Without a standard library to load, from the `rust-src` component, the workspace
+    /// is loaded without it.
+    pub fn load(manifest: &Path, config: &CargoConfig) -> Result<Self, EzError> {
+        let _t = timings::phase(Phase::CrateGraph);
+        let abs = AbsPathBuf::try_from(manifest.to_owned())
+            .map_err(|_| format!("Failed to find {:?}", manifest))?;
+        let project_manifest =
+            ProjectManifest::from_manifest_file(abs).map_err(|e| e.to_string())?;
+        let project = ProjectWorkspace::load(project_manifest.clone(), config, &|_| {})
+            .or_else(|_| {
+                let config = CargoConfig { no_sysroot: true, ..config.clone() };
+                ProjectWorkspace::load(project_manifest, &config, &|_| {})
+            })
+            .map_err(|e| format!("Failed to load `{}`: {}", manifest.display(), e))?;
+
+        let proc_macros = env::current_exe()
+            .and_then(|exe| ProcMacroClient::extern_process(exe, &[PROC_MACRO_SERVER]))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to start the proc macro server, {}", e);
+                ProcMacroClient::dummy()
+            });
+
+        // The crate graph only loads crate roots, the files of their modules are found
+        // by walking the roots of every package.
+        let roots = project.to_roots();
+        let mut loader = Loader::default();
+        for root in &roots {
+            for dir in &root.include {
+                let files = walk_dirs(dir.as_ref()).filter(|file| {
+                    file.extension().map_or(false, |ext| ext == "rs")
+                        && !root.exclude.iter().any(|dir| file.starts_with(dir))
+                });
+                for file in files {
+                    loader.load(&file);
+                }
+            }
+        }
+        let crate_graph =
+            project.to_crate_graph(config.target.as_deref(), &proc_macros, &mut |path| {
+                loader.load(path.as_ref())
+            });
+
+        // Files go in the innermost root that includes them, the rest in a last library
+        // root.
+        let mut sets = (0..=roots.len()).map(|_| FileSet::default()).collect::<Vec<_>>();
+        for (path, &id) in &loader.files {
+            let root = roots
+                .iter()
+                .enumerate()
+                .flat_map(|(i, root)| root.include.iter().map(move |dir| (i, dir)))
+                .filter(|(_, dir)| path.starts_with(dir))
+                .max_by_key(|(_, dir)| Path::components(dir.as_ref()).count())
+                .map_or(roots.len(), |(i, _)| i);
+            sets[root].insert(id, vfs_path(&path.display().to_string()));
+        }
+
+        let mut db = MacroExpander::new();
+        let (mut local, mut library) = (FxHashSet::default(), FxHashSet::default());
+        for (i, set) in sets.into_iter().enumerate() {
+            let root_id = SourceRootId(i as u32);
+            let is_member = roots.get(i).map_or(false, |root| root.is_member);
+            // Members are what changes, everything else is set once.
+            let durability = if is_member { Durability::LOW } else { Durability::HIGH };
+            let root = if is_member {
+                local.insert(root_id);
+                SourceRoot::new_local(set)
+            } else {
+                library.insert(root_id);
+                SourceRoot::new_library(set)
+            };
+            for id in root.iter() {
+                let text = std::mem::take(&mut loader.texts[id.0 as usize]);
+                db.set_file_text_with_durability(id, Arc::new(text), durability);
+                db.set_file_source_root_with_durability(id, root_id, durability);
+            }
+            db.set_source_root_with_durability(root_id, Arc::new(root), durability);
+        }
+        db.set_local_roots_with_durability(Arc::new(local), Durability::HIGH);
+        db.set_library_roots_with_durability(Arc::new(library), Durability::HIGH);
+        db.set_crate_graph_with_durability(Arc::new(crate_graph), Durability::HIGH);
+
+        Ok(Self { db, files: loader.files, _proc_macros: proc_macros })
+    }
+
+    /// Expand every macro invocation in `file`, reported to the `emitter` as `path`.
+    /// `None` when the file isn't part of the workspace.
+    ///
+    /// Any expansion that fails to finish within `limits` is reported to the `emitter`.
+    pub fn expand_file(
+        &self,
+        file: &Path,
+        path: &str,
+        limits: &mut ExpansionLimits,
+        emitter: &mut Emitter,
+    ) -> Option<Vec<Expansion>> {
+        let id = self.file_id(file)?;
+        Some(expand_calls(&self.db, id, path, limits, emitter))
+    }
+
+    /// Replace the text of `file`, only what depends on it is computed again. Returns
+    /// whether the file is part of the workspace.
+    pub fn set_file_text(&mut self, file: &Path, text: &str) -> bool {
+        let id = match self.file_id(file) {
+            Some(id) => id,
+            None => return false,
+        };
+        if *self.db.file_text(id) != *text {
+            self.db.set_file_text(id, Arc::new(text.to_owned()));
+        }
+        true
+    }
+
+    fn file_id(&self, file: &Path) -> Option<FileId> {
+        self.files
+            .get(file)
+            .or_else(|| self.files.get(&file.canonicalize().ok()?))
+            .copied()
+    }
+}
+
+/// Gives every file the crate graph or a package root needs an id, each file once.
+#[derive(Default)]
+struct Loader {
+    files: FxHashMap<PathBuf, FileId>,
+    /// The text of every file by id.
+    texts: Vec<String>,
+}
+
+impl Loader {
+    fn load(&mut self, path: &Path) -> Option<FileId> {
+        if let Some(&id) = self.files.get(path) {
+            return Some(id);
+        }
+        let text = fs::read_to_string(path).ok()?;
+        let id = FileId(self.texts.len() as u32);
+        self.texts.push(text);
+        self.files.insert(path.to_owned(), id);
+        Some(id)
+    }
+}
+
+/// Expand only the innermost macro call at `offset` in `text`, the file at `path`,
+/// `None` when there is no call there.
+pub fn expand_at(
+    path: &str,
+    text: &str,
+    offset: TextSize,
+    cfg: &CfgOptions,
+    edition: Edition,
+    limits: &mut ExpansionLimits,
+) -> Option<Result<String, ExpansionError>> {
+    let (db, ids) = MacroExpander::parse_crate(vec![(path, text)], cfg, edition);
+    let file = Semantics::new(&db).parse(ids[0]);
+    // The innermost call is the one the cursor is on.
+    let range =
+        find_node_at_offset::<MacroCall>(file.syntax(), offset)?.syntax().text_range();
+
+    let expanded = {
+        let _t = timings::phase(Phase::Expansion);
+        expand_limited(&db, ids[0], range, limits)
+    };
+    timings::queries(db.take_query_times());
+    Some(expanded)
+}
+
+/// Expand the macro call at `range` on another thread, giving up when it takes longer
+/// than the limits allow.
+///
+/// A thread that is given up on is cancelled, it unwinds at the next salsa query it
+/// runs. A single expansion step can't be interrupted, rust-analyzer caps the number of
+/// tokens one step produces and the output is checked against `max_output` before and
+/// while it is formatted.
+fn expand_limited(
+    db: &MacroExpander,
+    file_id: FileId,
+    range: TextRange,
+    limits: &mut ExpansionLimits,
+) -> Result<String, ExpansionError> {
+    if limits.budget == Duration::default() {
+        return Err(ExpansionError::OverBudget);
+    }
+
+    let max_output = limits.max_output;
+    let snap = db.snapshot();
+    let cancelled = snap.cancelled.clone();
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        let sema = Semantics::new(&*snap);
+        let source = sema.parse(file_id);
+        let expanded = find_node_at_range::<MacroCall>(source.syntax(), range)
+            .and_then(|mac| expand_macros(&sema, &mac))
+            .ok_or(ExpansionError::Failed)
+            .and_then(|node| {
+                let size = usize::from(node.text_range().len());
+                if size > max_output {
+                    Err(ExpansionError::TooLarge(size))
+                } else {
+                    insert_whitespaces(node, max_output)
+                }
+            });
+        let _ = send.send((expanded, snap.take_query_times()));
+    });
+
+    let timeout = limits.timeout.min(limits.budget);
+    let start = Instant::now();
+    let res = recv.recv_timeout(timeout);
+    limits.budget = limits.budget.checked_sub(start.elapsed()).unwrap_or_default();
+    if res.is_err() {
+        cancelled.store(true, Ordering::Relaxed);
+    }
+
+    match res {
+        Ok((expanded, queries)) => {
+            timings::queries(queries);
+            expanded
+        }
+        Err(RecvTimeoutError::Timeout) if timeout < limits.timeout => {
+            Err(ExpansionError::OverBudget)
+        }
+        Err(RecvTimeoutError::Timeout) => Err(ExpansionError::TimedOut(timeout)),
+        // The expansion panicked.
+        Err(RecvTimeoutError::Disconnected) => Err(ExpansionError::Failed),
+    }
+}
+
 #[test]
 fn call_mac_expand() {
     use syntax::AstNode;
 
     let text = include_str!("../fixtures/mbe.rs");
-    let (db, id) = MacroExpander::parse_crate(vec![text]);
+    let (db, id) = MacroExpander::parse_crate(
+        vec![("fixtures/mbe.rs", text)],
+        &CfgOptions::default(),
+        Edition::Edition2018,
+    );
 
     let db = hir::Semantics::new(&db);
 
@@ -246,6 +749,137 @@ fn call_mac_expand() {
     }
     println!(
         "{}",
-        expand_macros(&db, &mac_call.unwrap()).map(insert_whitespaces).unwrap()
+        expand_macros(&db, &mac_call.unwrap())
+            .map(|node| insert_whitespaces(node, usize::MAX))
+            .unwrap()
+            .unwrap()
+    );
+}
+
+#[test]
+fn modules_resolve_by_path() {
+    let lib = "#[macro_use]\nmod a;\nmac!();\n";
+    let (db, ids) = MacroExpander::parse_crate(
+        vec![
+            ("/ws/src/lib.rs", lib),
+            ("/ws/src/a.rs", "macro_rules! mac { () => { struct A; } }\n"),
+        ],
+        &CfgOptions::default(),
+        Edition::Edition2018,
+    );
+
+    let start = TextSize::from(lib.find("mac!").unwrap() as u32);
+    let call = TextRange::at(start, TextSize::of("mac!()"));
+    let expanded = expand_limited(&db, ids[0], call, &mut ExpansionLimits::default());
+    assert!(expanded.unwrap().contains("struct A"));
+}
+
+#[test]
+fn workspace_expands_dependency_macros() {
+    let root =
+        env::temp_dir().join(format!("ruma-check-workspace-{}", std::process::id()));
+    let write = |path: &str, text: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    };
+    write(
+        "app/Cargo.toml",
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\
+         [dependencies]\ndep = { path = \"../dep\" }\n[workspace]\n",
+    );
+    write("app/src/lib.rs", "mod a;\n");
+    write("app/src/a.rs", "dep::mac!();\n");
+    write("dep/Cargo.toml", "[package]\nname = \"dep\"\nversion = \"0.1.0\"\n");
+    write(
+        "dep/src/lib.rs",
+        "#[macro_export]\nmacro_rules! mac { () => { struct A; } }\n",
+    );
+
+    let config = CargoConfig { no_sysroot: true, ..CargoConfig::default() };
+    let workspace = Workspace::load(&root.join("app/Cargo.toml"), &config);
+    let mut emitter = Emitter::default();
+    let expansions = workspace.map(|ws| {
+        ws.expand_file(
+            &root.join("app/src/a.rs"),
+            "app/src/a.rs",
+            &mut ExpansionLimits::default(),
+            &mut emitter,
+        )
+    });
+    fs::remove_dir_all(&root).unwrap();
+
+    let expansions = expansions.unwrap().unwrap();
+    assert_eq!(expansions.len(), 1);
+    assert!(expansions[0].expanded.as_ref().unwrap().contains("struct A"));
+}
+
+#[test]
+fn expand_all_in_file() {
+    let text = include_str!("../fixtures/mbe.rs");
+    let mut emitter = Emitter::default();
+    let expansions = expand_file(
+        "fixtures/mbe.rs",
+        text,
+        &CfgOptions::default(),
+        Edition::Edition2018,
+        &mut ExpansionLimits::default(),
+        &mut emitter,
     );
+
+    assert_eq!(expansions.len(), 1);
+    assert_eq!(expansions[0].name, "mac_exp");
+    assert!(expansions[0].expanded.as_ref().unwrap().contains("struct Test"));
+    assert!(!emitter.found_errors());
+}
+
+#[test]
+fn expand_at_cursor() {
+    let text = include_str!("../fixtures/mbe.rs");
+    let expand = |offset: usize| {
+        expand_at(
+            "fixtures/mbe.rs",
+            text,
+            TextSize::from(offset as u32),
+            &CfgOptions::default(),
+            Edition::Edition2018,
+            &mut ExpansionLimits::default(),
+        )
+    };
+
+    let call = text.find("mac_exp! {").unwrap();
+    assert!(expand(call + 10).unwrap().unwrap().contains("struct Test"));
+    assert!(expand(text.find("use crate").unwrap()).is_none());
+}
+
+#[test]
+fn expansion_limits() {
+    let text = include_str!("../fixtures/mbe.rs");
+    let mut emitter = Emitter::default();
+    let mut limits = ExpansionLimits { max_output: 8, ..Default::default() };
+    let expansions = expand_file(
+        "fixtures/mbe.rs",
+        text,
+        &CfgOptions::default(),
+        Edition::Edition2018,
+        &mut limits,
+        &mut emitter,
+    );
+
+    assert!(matches!(expansions[0].expanded, Err(ExpansionError::TooLarge(_))));
+    assert!(emitter.found_errors());
+
+    let mut emitter = Emitter::default();
+    let mut limits =
+        ExpansionLimits { budget: Duration::default(), ..Default::default() };
+    let expansions = expand_file(
+        "fixtures/mbe.rs",
+        text,
+        &CfgOptions::default(),
+        Edition::Edition2018,
+        &mut limits,
+        &mut emitter,
+    );
+
+    assert_eq!(expansions[0].expanded, Err(ExpansionError::OverBudget));
 }
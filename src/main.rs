@@ -1,108 +1,352 @@
 use std::{
-    convert::TryInto,
     env, fs,
+    io::{self, BufWriter, Write},
     path::{Path, PathBuf},
 };
 
-use project_model::PackageData;
-
-mod error;
-mod macro_exp;
+mod cli;
+mod diag_diff;
+mod diff;
+mod expand_diff;
+mod git;
+mod lsp;
 mod project;
-mod rules;
-
-use error::Emitter;
-use project::CargoInfo;
+mod server;
 
-pub type EzError = Box<dyn std::error::Error>;
+use baseline::Baseline;
+use cfg_eval::CfgOptions;
+use cli::{Args, Command, Report};
+use error::{ColorChoice, Emitter, MessageFormat};
+use git::Changes;
+use macro_exp::ExpansionLimits;
+use project::{CargoInfo, Package};
+use ruma_check::{
+    baseline, cfg_eval, error, macro_exp, rules, timings, walk_dirs, EzError,
+};
+use rustc_hash::FxHashMap;
+use syntax::{Parse, SourceFile};
 
 fn main() {
-    let args = env::args().collect::<Vec<_>>();
+    let raw_args = env::args().skip(1).collect::<Vec<_>>();
+    // Started by a `macro_exp::Workspace` to expand proc macros, never by a user.
+    if raw_args.first().map(String::as_str) == Some(macro_exp::PROC_MACRO_SERVER) {
+        proc_macro_srv::cli::run().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        return;
+    }
+    let args = Args::parse(&raw_args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let socket = args.socket.clone().unwrap_or_else(server::default_socket);
+    let loc = env::current_dir().expect("No current directory found.");
 
+    if args.client {
+        let mut forwarded = server::forwarded_args(&raw_args);
+        // The server can't see our terminal so decide on color for it.
+        if args.color == ColorChoice::Auto && args.color.stderr() {
+            forwarded.extend(vec!["--color".to_owned(), "always".to_owned()]);
+        }
+        let stdout = args.message_format != MessageFormat::Human;
+        match server::forward(&socket, &loc, &forwarded, stdout) {
+            Ok(code) => std::process::exit(code),
+            // Still give an answer, just a slower one.
+            Err(e) => eprintln!("{}, checking without the server", e),
+        }
+    }
+
+    if args.timings {
+        timings::enable();
+    }
+
+    // Human output goes with the rest of the logging, everything else is for tools.
+    let (out, color): (Box<dyn Write>, _) = match args.message_format {
+        MessageFormat::Human => (Box::new(io::stderr()), args.color.stderr()),
+        _ => (Box::new(io::stdout()), false),
+    };
     let mut emitter = Emitter::default();
-    match args.as_slice() {
-        [_] => {
-            let loc = env::current_dir().expect("No current directory found.");
-            let root =
-                CargoInfo::build_crate_root(&loc).expect("Failed to parse Cargo.toml");
-            check_workspace(root, &mut emitter).unwrap_or_else(|e| {
+    configure_emitter(&loc, &args, &mut emitter, out, color).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    // Only set by `diff`, which fails when the head revision adds diagnostics.
+    let mut new_diagnostics = false;
+    match &args.command {
+        Command::Check => {
+            let root = CargoInfo::build_crate_root(&loc, &args).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+            let mut expander = if args.expand {
+                Some(project::load_expander(&loc, &args).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }))
+            } else {
+                None
+            };
+            let crates = changes(&loc, &args)
+                .and_then(|changes| {
+                    let expander = expander.as_mut();
+                    check_workspace(
+                        &root,
+                        &args,
+                        changes.as_ref(),
+                        None,
+                        expander,
+                        &mut emitter,
+                    )
+                })
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+            write_reports(&loc, &args, &emitter, &crates).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        }
+        Command::ExpandDiff { old, new } => {
+            expand_diff::expand_diff(&loc, old, new, &args, &mut emitter).unwrap_or_else(
+                |e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            );
+        }
+        Command::Diff { base, head } => {
+            let introduced = diag_diff::diag_diff(&loc, base, head, &args, &mut emitter)
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+            new_diagnostics = introduced > 0;
+        }
+        Command::Server => {
+            server::serve(&socket).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        }
+        Command::Lsp => {
+            lsp::run(&args).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        }
+        Command::InstallHook => {
+            let hook = git::install_hook(&loc).unwrap_or_else(|e| {
                 eprintln!("{}", e);
                 std::process::exit(1);
             });
+            eprintln!("Installed pre-commit hook at {}", hook.display());
         }
-        [] => panic!(),
-        _ => panic!(),
     }
 
-    if emitter.found_errors() {
-        emitter.emit().unwrap_or_else(|e| {
-            eprintln!("{}", e);
-            std::process::exit(1);
-        });
-        std::process::exit(1);
+    if let Some(report) = timings::report() {
+        eprint!("{}", report);
     }
+
+    let code = if new_diagnostics { 1 } else { emitter.exit_code() };
+    emitter.emit().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    std::process::exit(code);
+}
+
+/// The files `--staged` or `--changed-since` limit the check to, `None` checks
+/// everything.
+fn changes(cwd: &Path, args: &Args) -> Result<Option<Changes>, EzError> {
+    Ok(match &args.changed_since {
+        _ if args.staged => Some(Changes::staged(cwd)?),
+        Some(rev) => Some(Changes::since(cwd, rev)?),
+        None => None,
+    })
 }
 
-fn check_workspace(info: CargoInfo, emitter: &mut Emitter) -> Result<(), EzError> {
-    for pack in info.work.packages() {
-        let p = &info.work[pack];
-        if p.is_member {
-            check_files(p, emitter)?;
+/// Every file parsed by a long running check along with the text it was parsed from.
+type Parses = FxHashMap<PathBuf, (String, Parse<SourceFile>)>;
+
+/// Check every selected member and path dependency, returns the name and directory of
+/// each one checked.
+///
+/// With `changes` only members containing a changed file are checked. With `parses` a
+/// file is only parsed again when its text changed. With an `expander` every macro call
+/// of the checked files is expanded, expansions that blow past the `args` limits are
+/// reported.
+fn check_workspace(
+    info: &CargoInfo,
+    args: &Args,
+    changes: Option<&Changes>,
+    mut parses: Option<&mut Parses>,
+    mut expander: Option<&mut macro_exp::Workspace>,
+    emitter: &mut Emitter,
+) -> Result<Vec<(String, PathBuf)>, EzError> {
+    // The budget is shared by the whole run.
+    let mut limits = args.limits;
+    let mut crates = vec![];
+    for p in info.checked() {
+        if args.selected(&p.name) {
+            let parses = parses.as_deref_mut();
+            let expander = expander.as_deref_mut();
+            let checked =
+                check_files(p, args, changes, parses, expander, &mut limits, emitter)?;
+            if let Some(dir) = checked {
+                crates.push((p.name.clone(), dir));
+            }
         }
     }
 
-    Ok(())
+    Ok(crates)
 }
 
-fn check_files(package: &PackageData, emitter: &mut Emitter) -> Result<(), EzError> {
-    // Infallible
-    let mut path: PathBuf = package.manifest.clone().try_into()?;
-    path.pop();
-    path.push("src");
+/// Check every file of `package`, returns the directory of the package or `None` if
+/// no file in it changed.
+///
+/// With `changes` the crate is only checked when a changed file is part of it, and
+/// per file rules only run on the changed files, read with `Changes::read`. Every rule
+/// is a per file rule so far, there are no crate wide rules to run for the rest of the
+/// crate. Each file is walked once for all feature sets of `cfg_sets`.
+fn check_files(
+    package: &Package,
+    args: &Args,
+    changes: Option<&Changes>,
+    mut parses: Option<&mut Parses>,
+    mut expander: Option<&mut macro_exp::Workspace>,
+    limits: &mut ExpansionLimits,
+    emitter: &mut Emitter,
+) -> Result<Option<PathBuf>, EzError> {
+    if changes.map_or(false, |c| !c.touches(&package.dir)) {
+        return Ok(None);
+    }
 
-    for file in walk_dirs(&path) {
-        let text = fs::read_to_string(&file)
-            .map_err(|_| format!("Failed to open file at {:?}", file))?;
+    let cfgs = cfg_sets(package, args);
+    emitter.set_target(Some(package.cargo_target()));
+    for file in package.sources() {
+        if changes.map_or(false, |c| !c.contains(&file)) {
+            continue;
+        }
+        let text = match changes {
+            Some(changes) => changes.read(&file)?,
+            None => fs::read_to_string(&file)
+                .map_err(|_| format!("Failed to open file at {:?}", file))?,
+        };
 
+        let _t = timings::file(&file);
+        if let Some(expander) = expander.as_deref_mut() {
+            // Files outside the workspace, like new ones, have nothing to expand with.
+            if expander.set_file_text(&file, &text) {
+                expander.expand_file(&file, &file.display().to_string(), limits, emitter);
+            }
+        }
         // Here is where the magic happens.
         // We validate all files found for this crate!
-        rules::validate_source(&file, &text, emitter)?;
+        let parse = match parses.as_deref_mut() {
+            Some(parses) => match parses.get(&file) {
+                Some((old, parse)) if *old == text => parse.clone(),
+                _ => {
+                    let parse = rules::parse(&text);
+                    parses.insert(file.clone(), (text, parse.clone()));
+                    parse
+                }
+            },
+            None => rules::parse(&text),
+        };
+        rules::validate_parsed(&file, &parse, &cfgs, emitter)?;
+        emitter.flush()?;
     }
 
-    Ok(())
+    Ok(Some(package.dir.clone()))
 }
 
-fn walk_dirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
-    Walker { dir_stack: vec![dir.to_owned()], files: vec![] }
+/// The cfg options to check `package` with, one for each feature set with
+/// `--feature-matrix`.
+///
+/// Features are only evaluated when a feature flag is given, otherwise code behind any
+/// feature is checked.
+fn cfg_sets(package: &Package, args: &Args) -> Vec<CfgOptions> {
+    let mut base =
+        args.target.as_deref().map_or_else(CfgOptions::default, CfgOptions::for_target);
+    base.merge(&package.cfg);
+
+    let sets = if args.feature_matrix {
+        package.feature_matrix()
+    } else if args.features.is_set() {
+        vec![package.enabled_features(&args.features)]
+    } else {
+        return vec![base];
+    };
+    sets.into_iter().map(|features| base.clone().with_features(features)).collect()
 }
 
-struct Walker {
-    dir_stack: Vec<PathBuf>,
-    files: Vec<PathBuf>,
+/// Write `args.message_format` to `out`, every `--report` that is a message format to
+/// its file and load the `--baseline`, relative paths are relative to `cwd`.
+///
+/// Reports name files relative to the repository `cwd` is in, or to `cwd` outside of
+/// one.
+fn configure_emitter<W: Write + 'static>(
+    cwd: &Path,
+    args: &Args,
+    emitter: &mut Emitter,
+    out: W,
+    color: bool,
+) -> Result<(), EzError> {
+    let root = git::repo_root(cwd).unwrap_or_else(|_| cwd.to_owned());
+    emitter.set_group_by_rule(args.group_by_rule);
+    emitter.add_sink(error::sink(
+        args.message_format,
+        out,
+        color,
+        args.group_by_rule,
+        &root,
+    ));
+    for report in &args.reports {
+        if let Report::Format(format, path) = report {
+            let path = cwd.join(path);
+            let file = fs::File::create(&path).map_err(|e| {
+                format!("Failed to create report `{}`: {}", path.display(), e)
+            })?;
+            emitter.add_sink(error::sink(
+                *format,
+                BufWriter::new(file),
+                false,
+                args.group_by_rule,
+                &root,
+            ));
+        }
+    }
+    if let Some(path) = &args.baseline {
+        emitter.set_baseline(Baseline::load(&cwd.join(path), cwd)?);
+    }
+    Ok(())
 }
 
-impl Iterator for Walker {
-    type Item = PathBuf;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.dir_stack.pop() {
-            Some(dir) => {
-                if dir.is_dir() {
-                    for entry in fs::read_dir(dir).ok()? {
-                        let entry = entry.ok()?;
-                        let path = entry.path();
-                        if path.is_dir() {
-                            self.dir_stack.push(path);
-                        } else {
-                            self.files.push(path);
-                        }
-                    }
-                    self.files.pop()
-                } else {
-                    None
-                }
+/// Write every `--report` that needs the whole run and the `--write-baseline`, relative
+/// paths are relative to `cwd`.
+fn write_reports(
+    cwd: &Path,
+    args: &Args,
+    emitter: &Emitter,
+    crates: &[(String, PathBuf)],
+) -> Result<(), EzError> {
+    for report in &args.reports {
+        match report {
+            Report::Html(path) => {
+                let path = cwd.join(path);
+                fs::write(&path, emitter.html_report(crates)).map_err(|e| {
+                    format!("Failed to write report to `{}`: {}", path.display(), e)
+                })?;
             }
-            None => self.files.pop(),
+            // Written as the run goes, see `configure_emitter`.
+            Report::Format(..) => {}
         }
     }
+    if let Some(path) = &args.write_baseline {
+        Baseline::from_diagnostics(cwd, emitter.diagnostics(), emitter.source_map())
+            .save(&cwd.join(path))?;
+    }
+    Ok(())
 }
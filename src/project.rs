@@ -1,28 +1,839 @@
-use std::{convert::TryInto, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
 
 use paths::AbsPathBuf;
-use project_model::{CargoConfig, CargoWorkspace};
+use project_model::{CargoConfig, CargoWorkspace, ProjectJson, ProjectJsonData};
 
+use crate::{
+    cfg_eval::CfgOptions,
+    cli::Args,
+    error::CargoTarget,
+    macro_exp::{self, Edition},
+    timings::{self, Phase},
+    walk_dirs, EzError,
+};
+
+/// The file describing a project that isn't built with cargo.
+pub const PROJECT_JSON: &str = "rust-project.json";
+
+/// A package of the workspace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Package {
+    pub name: String,
+    /// `0.0.0` when it isn't known, like for the crates of a `rust-project.json`.
+    pub version: String,
+    /// The `Cargo.toml` of the package, or the `rust-project.json` it is described in.
+    pub manifest: PathBuf,
+    /// The directory the package is in.
+    pub dir: PathBuf,
+    /// The directories holding the source files of the package.
+    pub include: Vec<PathBuf>,
+    /// Directories inside `include` that are not part of the package.
+    pub exclude: Vec<PathBuf>,
+    pub is_member: bool,
+    /// A package outside the workspace that a member depends on by path or patches in,
+    /// only set with `--path-deps`.
+    pub is_path_dep: bool,
+    /// Every feature the package declares and the features each one enables, optional
+    /// dependencies included. Only known for members and path dependencies.
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Cfgs that are always set, from a `rust-project.json`.
+    pub cfg: CfgOptions,
+    pub edition: Edition,
+}
+
+/// Which features to check with, like cargo's flags of the same name.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Features {
+    /// `--features`, a feature of one package is written `package/feature`.
+    pub features: Vec<String>,
+    /// `--all-features`
+    pub all: bool,
+    /// `--no-default-features`
+    pub no_default: bool,
+}
+
+impl Features {
+    /// Was any feature flag given, without one features are not evaluated at all.
+    pub fn is_set(&self) -> bool {
+        !self.features.is_empty() || self.all || self.no_default
+    }
+}
+
+impl Package {
+    fn cargo(name: String, manifest: PathBuf, is_member: bool) -> Self {
+        let dir = manifest.parent().unwrap_or(&manifest).to_owned();
+        Self {
+            name,
+            version: "0.0.0".to_owned(),
+            include: vec![dir.join("src")],
+            exclude: vec![],
+            dir,
+            manifest,
+            is_member,
+            is_path_dep: false,
+            features: BTreeMap::new(),
+            cfg: CfgOptions::default(),
+            edition: Edition::default(),
+        }
+    }
+
+    /// Read the features of the package from its own manifest.
+    fn read_features(&mut self) {
+        if let Ok(toml) = read_manifest(&self.manifest) {
+            self.features = declared_features(&toml);
+        }
+    }
+
+    /// Read the edition and version from the manifest, for packages `cargo metadata`
+    /// didn't load.
+    fn read_metadata(&mut self) {
+        if let Ok(toml) = read_manifest(&self.manifest) {
+            let manifest = &self.manifest;
+            let field = |key| package_field(&toml, manifest, key);
+            self.edition =
+                field("edition").as_deref().and_then(Edition::parse).unwrap_or_default();
+            if let Some(version) = field("version") {
+                self.version = version;
+            }
+        }
+    }
+
+    /// How cargo's JSON messages name the package and the checked target, a package is
+    /// checked as a whole so the target is its library, or its binary without one.
+    pub fn cargo_target(&self) -> CargoTarget {
+        let lib = self.dir.join("src").join("lib.rs");
+        let bin = self.dir.join("src").join("main.rs");
+        let (kind, src_path) =
+            if !lib.is_file() && bin.is_file() { ("bin", bin) } else { ("lib", lib) };
+        CargoTarget {
+            package_id: format!(
+                "{} {} (path+file://{})",
+                self.name,
+                self.version,
+                self.dir.display()
+            ),
+            manifest_path: self.manifest.display().to_string(),
+            kind: kind.to_owned(),
+            name: if kind == "lib" {
+                self.name.replace('-', "_")
+            } else {
+                self.name.clone()
+            },
+            src_path: src_path.display().to_string(),
+            edition: self.edition.as_str().to_owned(),
+        }
+    }
+
+    /// The features `selected` turns on, along with every feature they enable.
+    pub fn enabled_features(&self, selected: &Features) -> BTreeSet<String> {
+        let mut todo = vec![];
+        if selected.all {
+            todo.extend(self.features.keys().cloned());
+        }
+        if !selected.no_default {
+            todo.push("default".to_owned());
+        }
+        for feature in &selected.features {
+            match feature.find('/') {
+                Some(slash) if feature[..slash] == self.name => {
+                    todo.push(feature[slash + 1..].to_owned())
+                }
+                Some(_) => {}
+                None => todo.push(feature.clone()),
+            }
+        }
+
+        let mut enabled = BTreeSet::new();
+        while let Some(feature) = todo.pop() {
+            let enables = match self.features.get(&feature) {
+                Some(enables) if enabled.insert(feature.clone()) => enables,
+                _ => continue,
+            };
+            for name in enables {
+                // `dep:name` is the optional dependency `name`, `dep/feature` a feature
+                // of a dependency that also turns the dependency on
+                // unless written `dep?/`.
+                let name = name.trim_start_matches("dep:");
+                match name.find('/') {
+                    Some(slash) if !name[..slash].ends_with('?') => {
+                        todo.push(name[..slash].to_owned())
+                    }
+                    Some(_) => {}
+                    None => todo.push(name.to_owned()),
+                }
+            }
+        }
+        enabled
+    }
+
+    /// The feature sets `--feature-matrix` checks: no features, the default features,
+    /// each feature on top of the defaults and every feature.
+    pub fn feature_matrix(&self) -> Vec<BTreeSet<String>> {
+        let mut sets = vec![
+            self.enabled_features(&Features { no_default: true, ..Features::default() }),
+            self.enabled_features(&Features::default()),
+        ];
+        for feature in self.features.keys().filter(|f| *f != "default") {
+            sets.push(self.enabled_features(&Features {
+                features: vec![feature.clone()],
+                ..Features::default()
+            }));
+        }
+        sets.push(self.enabled_features(&Features { all: true, ..Features::default() }));
+
+        let mut seen = BTreeSet::new();
+        sets.retain(|set| seen.insert(set.clone()));
+        sets
+    }
+
+    /// Every Rust file of the package, in sorted order.
+    pub fn sources(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.include.iter().flat_map(|dir| walk_dirs(dir)).filter(move |file| {
+            file.extension().map_or(false, |ext| ext == "rs")
+                && !self.exclude.iter().any(|dir| file.starts_with(dir))
+        })
+    }
+}
+
+/// The packages of a cargo workspace or of a `rust-project.json`.
 #[derive(Clone, Debug)]
 pub struct CargoInfo {
-    pub work: CargoWorkspace,
+    pub packages: Vec<Package>,
 }
 
 impl CargoInfo {
-    pub fn build_crate_root<P: AsRef<Path>>(p: P) -> Result<Self, String> {
-        let mut path = p.as_ref().to_owned();
-        if !path.ends_with("Cargo.toml") {
-            path.push("Cargo.toml");
+    /// Load the project at `p`, a `rust-project.json` wins over a `Cargo.toml` like it
+    /// does for rust-analyzer.
+    ///
+    /// Cargo workspaces are loaded from `cargo metadata` with the features and target of
+    /// `args`. With `--offline` cargo is not allowed to use the network, and if metadata
+    /// still fails the members are read from the `Cargo.toml` files directly. That is
+    /// enough for the syntactic rules. `--frozen` is `--offline` that fails first when
+    /// `Cargo.lock` is out of date. With `--path-deps` the path dependencies of members
+    /// are found as well.
+    pub fn build_crate_root<P: AsRef<Path>>(p: P, args: &Args) -> Result<Self, String> {
+        let offline = args.offline || args.frozen;
+        let path = project_manifest(p.as_ref());
+        if path.ends_with(PROJECT_JSON) {
+            return Self::from_project_json(&path).map_err(|e| e.to_string());
         }
-        let path: AbsPathBuf = path
+        let abs: AbsPathBuf = path
+            .clone()
             .try_into()
             .map_err(|_| format!("Failed to find {:?}", p.as_ref()))?;
 
-        let config = CargoConfig::default();
+        let config = cargo_config(args);
+
+        let _t = timings::phase(Phase::Metadata);
+        // rust-analyzer's loader has no way to pass `--frozen` on, so cargo is run
+        // directly and its metadata used instead of checking first and loading again.
+        let work = if args.frozen {
+            Ok(frozen_metadata(&path, args)?)
+        } else {
+            with_offline(offline, || Self::load_metadata(&abs, &config))
+        };
+
+        let mut info = match work {
+            Ok(info) => info,
+            Err(e) if offline => {
+                eprintln!(
+                    "`cargo metadata` failed: {}, reading Cargo.toml files instead",
+                    e
+                );
+                Self::from_manifests(&path).map_err(|e| e.to_string())?
+            }
+            Err(e) => return Err(e),
+        };
+        if args.path_deps {
+            info.find_path_deps(&path);
+        }
+        Ok(info)
+    }
+
+    /// The packages rust-analyzer loads from `cargo metadata` for `manifest`.
+    fn load_metadata(
+        manifest: &AbsPathBuf,
+        config: &CargoConfig,
+    ) -> Result<Self, String> {
+        let work = CargoWorkspace::from_cargo_metadata(manifest, config, &|_| {})
+            .map_err(|e| e.to_string())?;
 
         Ok(Self {
-            work: CargoWorkspace::from_cargo_metadata(&path, &config, &|_| {})
-                .map_err(|e| e.to_string())?,
+            packages: work
+                .packages()
+                .map(|pack| {
+                    let p = &work[pack];
+                    let mut package = Package::cargo(
+                        p.name.clone(),
+                        p.manifest.clone().into(),
+                        p.is_member,
+                    );
+                    package.version = p.version.to_string();
+                    package.edition = Edition::from_ra(p.edition);
+                    if p.is_member {
+                        package.read_features();
+                    }
+                    package
+                })
+                .collect(),
         })
     }
+
+    /// The members of the workspace with the root `manifest` without asking cargo.
+    ///
+    /// Only workspace members are found, dependencies need the resolver.
+    pub fn from_manifests(manifest: &Path) -> Result<Self, EzError> {
+        let root = read_manifest(manifest)?;
+        let dir = manifest.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut manifests = vec![];
+        if root.get("package").is_some() {
+            manifests.push(manifest.to_owned());
+        }
+        let workspace = root.get("workspace");
+        let paths = |key: &str| -> Result<Vec<PathBuf>, EzError> {
+            let mut paths = vec![];
+            let patterns = workspace.and_then(|w| w.get(key)).and_then(|m| m.as_array());
+            for pattern in patterns.into_iter().flatten().filter_map(|m| m.as_str()) {
+                let pattern = dir.join(pattern);
+                let pattern = pattern.to_str().ok_or("Invalid workspace member path")?;
+                for path in glob::glob(pattern)? {
+                    paths.push(path?);
+                }
+            }
+            Ok(paths)
+        };
+        let exclude = paths("exclude")?;
+        for member in paths("members")? {
+            let member_manifest = member.join("Cargo.toml");
+            if !exclude.contains(&member)
+                && member_manifest.is_file()
+                && !manifests.contains(&member_manifest)
+            {
+                manifests.push(member_manifest);
+            }
+        }
+
+        let mut packages = vec![];
+        for manifest in manifests {
+            let name = package_name(&read_manifest(&manifest)?)
+                .ok_or(format!("No package name in `{}`", manifest.display()))?;
+            let mut package = Package::cargo(name, manifest, true);
+            package.read_features();
+            package.read_metadata();
+            packages.push(package);
+        }
+        Ok(Self { packages })
+    }
+
+    /// The crates of a `rust-project.json`, loaded by rust-analyzer so paths resolve
+    /// relative to the file and `source` defaults to the directory of the root module.
+    ///
+    /// The pinned rust-analyzer only reads the 2015 and 2018 editions.
+    pub fn from_project_json(path: &Path) -> Result<Self, EzError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read `{}`: {}", path.display(), e))?;
+        Self::project_json(path, &text)
+            .map_err(|e| format!("Failed to parse `{}`: {}", path.display(), e).into())
+    }
+
+    fn project_json(path: &Path, text: &str) -> Result<Self, EzError> {
+        let abs: AbsPathBuf = path
+            .to_owned()
+            .try_into()
+            .map_err(|_| format!("Failed to find {:?}", path))?;
+        let base = abs.parent().ok_or("No parent directory")?;
+        let project =
+            ProjectJson::new(base, serde_json::from_str::<ProjectJsonData>(text)?);
+        // rust-analyzer keeps the cfgs as `CfgOptions`, which can't be listed again.
+        let json: serde_json::Value = serde_json::from_str(text)?;
+
+        let mut packages = vec![];
+        for (idx, (_, krate)) in project.crates().enumerate() {
+            let cfg = json["crates"][idx]["cfg"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|cfg| cfg.as_str().map(str::to_owned))
+                .collect::<Vec<_>>();
+            let dir: PathBuf =
+                krate.root_module.parent().unwrap_or(base).to_path_buf().into();
+            packages.push(Package {
+                // Crates only need a name when something depends on them.
+                version: "0.0.0".to_owned(),
+                name: krate
+                    .display_name
+                    .as_ref()
+                    .map_or_else(|| format!("crate{}", idx), ToString::to_string),
+                manifest: path.to_owned(),
+                include: krate.include.iter().cloned().map(Into::into).collect(),
+                exclude: krate.exclude.iter().cloned().map(Into::into).collect(),
+                dir,
+                is_member: krate.is_workspace_member,
+                is_path_dep: false,
+                features: BTreeMap::new(),
+                cfg: CfgOptions::from_list(&cfg),
+                edition: Edition::from_ra(krate.edition),
+            });
+        }
+        Ok(Self { packages })
+    }
+
+    /// The packages that are workspace members.
+    pub fn members(&self) -> impl Iterator<Item = &Package> {
+        self.packages.iter().filter(|p| p.is_member)
+    }
+
+    /// The packages that are checked, members and path dependencies.
+    pub fn checked(&self) -> impl Iterator<Item = &Package> {
+        self.packages.iter().filter(|p| p.is_member || p.is_path_dep)
+    }
+
+    /// Mark every package members depend on by path, directly or through other path
+    /// dependencies or a `[patch]` in `root`, the workspace manifest.
+    ///
+    /// Packages that weren't loaded, like when reading manifests without cargo, are
+    /// added.
+    fn find_path_deps(&mut self, root: &Path) {
+        let canonical =
+            |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let mut todo = self.members().map(|p| p.manifest.clone()).collect::<Vec<_>>();
+        todo.push(root.to_owned());
+        let mut seen = BTreeSet::new();
+        while let Some(manifest) = todo.pop() {
+            if !seen.insert(canonical(&manifest)) {
+                continue;
+            }
+            let toml = match read_manifest(&manifest) {
+                Ok(toml) => toml,
+                Err(_) => continue,
+            };
+            let dir = manifest.parent().unwrap_or_else(|| Path::new(""));
+            for dep in path_deps(&toml, dir) {
+                let dep = dep.join("Cargo.toml");
+                let known = canonical(&dep);
+                match self.packages.iter_mut().find(|p| canonical(&p.manifest) == known) {
+                    Some(p) if p.is_member || p.is_path_dep => {}
+                    Some(p) => {
+                        p.is_path_dep = true;
+                        p.read_features();
+                    }
+                    None => {
+                        let name = match read_manifest(&dep)
+                            .ok()
+                            .as_ref()
+                            .and_then(package_name)
+                        {
+                            Some(name) => name,
+                            None => continue,
+                        };
+                        let mut package = Package::cargo(name, dep.clone(), false);
+                        package.is_path_dep = true;
+                        package.read_features();
+                        package.read_metadata();
+                        self.packages.push(package);
+                    }
+                }
+                todo.push(dep);
+            }
+        }
+    }
+}
+
+/// The `[features]` of `manifest` with an implicit feature for each optional
+/// dependency.
+fn declared_features(manifest: &toml::Value) -> BTreeMap<String, Vec<String>> {
+    let mut features = BTreeMap::new();
+    for (name, enables) in
+        manifest.get("features").and_then(|f| f.as_table()).into_iter().flatten()
+    {
+        let enables = enables.as_array().into_iter().flatten();
+        features.insert(
+            name.clone(),
+            enables.filter_map(|f| f.as_str().map(str::to_owned)).collect(),
+        );
+    }
+    for table in &["dependencies", "build-dependencies"] {
+        let deps = manifest.get(*table).and_then(|d| d.as_table()).into_iter().flatten();
+        for (name, dep) in deps {
+            if dep.get("optional").and_then(|o| o.as_bool()) == Some(true) {
+                features.entry(name.clone()).or_insert_with(Vec::new);
+            }
+        }
+    }
+    features
+}
+
+/// The manifest of the project at `p`, a `rust-project.json` wins over a `Cargo.toml`
+/// like it does for rust-analyzer. `p` may be the manifest itself.
+pub fn project_manifest(p: &Path) -> PathBuf {
+    if p.ends_with(PROJECT_JSON) || p.ends_with("Cargo.toml") {
+        p.to_owned()
+    } else if p.join(PROJECT_JSON).is_file() {
+        p.join(PROJECT_JSON)
+    } else {
+        p.join("Cargo.toml")
+    }
+}
+
+/// How rust-analyzer loads cargo workspaces for `args`.
+pub fn cargo_config(args: &Args) -> CargoConfig {
+    CargoConfig {
+        features: args.features.features.clone(),
+        all_features: args.features.all,
+        no_default_features: args.features.no_default,
+        target: args.target.clone(),
+        ..CargoConfig::default()
+    }
+}
+
+/// Run `f`, which has rust-analyzer run cargo, without letting cargo use the network
+/// when `offline`.
+///
+/// The environment is the only way to pass `--offline` through. It is set for the call
+/// only and put back exactly as it was, nothing else runs on other threads while
+/// loading.
+pub fn with_offline<T>(offline: bool, f: impl FnOnce() -> T) -> T {
+    if !offline {
+        return f();
+    }
+    let was_offline = env::var_os("CARGO_NET_OFFLINE");
+    env::set_var("CARGO_NET_OFFLINE", "true");
+    let res = f();
+    match was_offline {
+        Some(value) => env::set_var("CARGO_NET_OFFLINE", value),
+        None => env::remove_var("CARGO_NET_OFFLINE"),
+    }
+    res
+}
+
+/// Load the project at `root` for expanding macros with the features and target of
+/// `args`, which runs `cargo check` to build its proc macros.
+pub fn load_expander(root: &Path, args: &Args) -> Result<macro_exp::Workspace, EzError> {
+    let config = CargoConfig { load_out_dirs_from_check: true, ..cargo_config(args) };
+    with_offline(args.offline || args.frozen, || {
+        macro_exp::Workspace::load(&project_manifest(root), &config)
+    })
+}
+
+/// The packages of `cargo metadata --frozen` for `manifest` with the features and
+/// target of `args`, fails when `Cargo.lock` is out of date.
+fn frozen_metadata(manifest: &Path, args: &Args) -> Result<CargoInfo, String> {
+    let mut cargo = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+    cargo
+        .args(&["metadata", "--format-version", "1", "--frozen", "--manifest-path"])
+        .arg(manifest);
+    if args.features.all {
+        cargo.arg("--all-features");
+    }
+    if args.features.no_default {
+        cargo.arg("--no-default-features");
+    }
+    if !args.features.features.is_empty() {
+        cargo.arg("--features").arg(args.features.features.join(" "));
+    }
+    if let Some(target) = &args.target {
+        cargo.arg("--filter-platform").arg(target);
+    }
+    let out = cargo
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| format!("Failed to run cargo: {}", e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "`cargo metadata --frozen` failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    let metadata = serde_json::from_slice(&out.stdout)
+        .map_err(|e| format!("Failed to parse `cargo metadata`: {}", e))?;
+    Ok(CargoInfo { packages: metadata_packages(&metadata) })
+}
+
+/// The packages listed in the output of `cargo metadata`.
+fn metadata_packages(metadata: &serde_json::Value) -> Vec<Package> {
+    let members = metadata["workspace_members"].as_array().cloned().unwrap_or_default();
+    let packages = metadata["packages"].as_array().map_or(&[][..], |p| p.as_slice());
+    packages
+        .iter()
+        .filter_map(|p| {
+            let is_member = members.contains(&p["id"]);
+            let mut package = Package::cargo(
+                p["name"].as_str()?.to_owned(),
+                p["manifest_path"].as_str()?.into(),
+                is_member,
+            );
+            package.version = p["version"].as_str()?.to_owned();
+            package.edition =
+                p["edition"].as_str().and_then(Edition::parse).unwrap_or_default();
+            if is_member {
+                package.read_features();
+            }
+            Some(package)
+        })
+        .collect()
+}
+
+/// The `package.<key>` string `manifest` at `path` declares, `<key>.workspace = true`
+/// takes it from the `[workspace.package]` of the closest manifest with a `[workspace]`
+/// like cargo.
+fn package_field(manifest: &toml::Value, path: &Path, key: &str) -> Option<String> {
+    match manifest.get("package")?.get(key)? {
+        value if value.get("workspace").and_then(|w| w.as_bool()) == Some(true) => {
+            let dirs = path.parent().into_iter().flat_map(Path::ancestors);
+            dirs.filter_map(|dir| read_manifest(&dir.join("Cargo.toml")).ok())
+                .find_map(|root| root.get("workspace").cloned())
+                .and_then(|w| w.get("package")?.get(key)?.as_str().map(str::to_owned))
+        }
+        value => value.as_str().map(str::to_owned),
+    }
+}
+
+fn package_name(manifest: &toml::Value) -> Option<String> {
+    manifest.get("package")?.get("name")?.as_str().map(str::to_owned)
+}
+
+/// The directory of every dependency of `manifest` given by path, `[patch]`es, target
+/// specific dependencies and `[workspace.dependencies]` included. `dir` is the directory
+/// of the manifest.
+fn path_deps(manifest: &toml::Value, dir: &Path) -> Vec<PathBuf> {
+    let dep_tables = |toml: &toml::Value| {
+        ["dependencies", "dev-dependencies", "build-dependencies"]
+            .iter()
+            .filter_map(|table| toml.get(*table))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let values = |key: &str| {
+        manifest.get(key).and_then(|t| t.as_table()).into_iter().flat_map(|t| t.values())
+    };
+
+    let mut tables = dep_tables(manifest);
+    for target in values("target") {
+        tables.extend(dep_tables(target));
+    }
+    tables.extend(values("patch").cloned());
+    // Members inherit these with `{ workspace = true }`, paths are relative to the root.
+    if let Some(deps) = manifest.get("workspace").and_then(|w| w.get("dependencies")) {
+        tables.push(deps.clone());
+    }
+    tables
+        .iter()
+        .filter_map(|table| table.as_table())
+        .flat_map(|table| table.values())
+        .filter_map(|dep| dep.get("path")?.as_str())
+        .map(|path| dir.join(path))
+        .collect()
+}
+
+fn read_manifest(path: &Path) -> Result<toml::Value, EzError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read `{}`: {}", path.display(), e))?;
+    Ok(text
+        .parse()
+        .map_err(|e| format!("Failed to parse `{}`: {}", path.display(), e))?)
+}
+
+#[test]
+fn manifests_without_cargo() {
+    let root =
+        env::temp_dir().join(format!("ruma-check-manifests-{}", std::process::id()));
+    let write = |path: &str, text: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    };
+    write(
+        "Cargo.toml",
+        "[workspace]\nmembers = [\"ruma\", \"ruma-*\"]\nexclude = [\"ruma-old\"]\n\
+         [workspace.package]\nedition = \"2021\"\nversion = \"0.3.0\"\n",
+    );
+    write("ruma/Cargo.toml", "[package]\nname = \"ruma\"\nedition = \"2018\"\n");
+    write(
+        "ruma-common/Cargo.toml",
+        "[package]\nname = \"ruma-common\"\nversion = \"0.2.0\"\n",
+    );
+    write(
+        "ruma-client/Cargo.toml",
+        "[package]\nname = \"ruma-client\"\nedition.workspace = true\n\
+         version.workspace = true\n",
+    );
+    write("ruma-old/Cargo.toml", "[package]\nname = \"ruma-old\"\n");
+    // Not a package.
+    fs::create_dir_all(root.join("ruma-docs")).unwrap();
+
+    let info = CargoInfo::from_manifests(&root.join("Cargo.toml"));
+    fs::remove_dir_all(&root).unwrap();
+
+    let info = info.unwrap();
+    let names = info.members().map(|p| p.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["ruma", "ruma-client", "ruma-common"]);
+    assert_eq!(info.packages[0].edition, Edition::Edition2018);
+    assert_eq!(info.packages[1].edition, Edition::Edition2021);
+    assert_eq!(info.packages[2].edition, Edition::Edition2015);
+    let versions = info.members().map(|p| p.version.as_str()).collect::<Vec<_>>();
+    assert_eq!(versions, vec!["0.0.0", "0.3.0", "0.2.0"]);
+
+    let target = info.packages[2].cargo_target();
+    assert_eq!(target.name, "ruma_common");
+    assert_eq!(target.kind, "lib");
+    assert!(target.package_id.starts_with("ruma-common 0.2.0 (path+file://"));
+}
+
+#[test]
+fn frozen_metadata_packages() {
+    let metadata = serde_json::json!({
+        "packages": [
+            {
+                "id": "ruma 0.1.0 (path+file:///ws/ruma)",
+                "name": "ruma",
+                "version": "0.1.0",
+                "manifest_path": "/ws/ruma/Cargo.toml",
+                "edition": "2021",
+            },
+            {
+                "id": "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                "name": "serde",
+                "version": "1.0.0",
+                "manifest_path": "/registry/serde-1.0.0/Cargo.toml",
+                "edition": "2015",
+            },
+        ],
+        "workspace_members": ["ruma 0.1.0 (path+file:///ws/ruma)"],
+    });
+
+    let packages = metadata_packages(&metadata);
+    let summary = packages
+        .iter()
+        .map(|p| (p.name.as_str(), p.version.as_str(), p.edition, p.is_member))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            ("ruma", "0.1.0", Edition::Edition2021, true),
+            ("serde", "1.0.0", Edition::Edition2015, false),
+        ]
+    );
+    assert_eq!(packages[0].dir, PathBuf::from("/ws/ruma"));
+}
+
+#[test]
+fn rust_project_json() {
+    let json = r#"{
+        "crates": [
+            {
+                "display_name": "ruma_common",
+                "root_module": "vendor/ruma-common/src/lib.rs",
+                "edition": "2018",
+                "deps": [],
+                "cfg": ["feature=\"std\""]
+            },
+            {
+                "root_module": "vendor/ruma/lib.rs",
+                "edition": "2015",
+                "deps": [{ "crate": 0, "name": "ruma_common" }],
+                "source": {
+                    "include_dirs": ["vendor/ruma"],
+                    "exclude_dirs": ["vendor/ruma/gen"]
+                },
+                "is_workspace_member": false
+            }
+        ]
+    }"#;
+    let info = CargoInfo::project_json(Path::new("/ws/rust-project.json"), json).unwrap();
+
+    assert_eq!(info.packages[0].name, "ruma_common");
+    assert_eq!(
+        info.packages[0].include,
+        vec![PathBuf::from("/ws/vendor/ruma-common/src")]
+    );
+    assert_eq!(
+        info.packages[0].cfg,
+        CfgOptions::from_list(&["feature=\"std\"".to_owned()])
+    );
+    assert_eq!(info.packages[1].name, "crate1");
+    assert_eq!(info.packages[1].exclude, vec![PathBuf::from("/ws/vendor/ruma/gen")]);
+    assert_eq!(info.members().count(), 1);
+    assert_eq!(info.packages[1].edition, Edition::Edition2015);
+}
+
+#[test]
+fn feature_sets() {
+    let mut package =
+        Package::cargo("ruma-events".to_owned(), "/ws/Cargo.toml".into(), false);
+    for (name, enables) in &[
+        ("default", vec!["client"]),
+        ("client", vec![]),
+        ("unstable-pre-spec", vec!["ruma-common/unstable-pre-spec", "markdown"]),
+        ("markdown", vec![]),
+    ] {
+        package
+            .features
+            .insert(name.to_string(), enables.iter().map(|s| s.to_string()).collect());
+    }
+    let set =
+        |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<BTreeSet<_>>();
+
+    assert_eq!(
+        package.enabled_features(&Features::default()),
+        set(&["client", "default"])
+    );
+    let selected = Features {
+        features: vec![
+            "ruma-events/unstable-pre-spec".to_owned(),
+            "ruma/full".to_owned(),
+        ],
+        no_default: true,
+        ..Features::default()
+    };
+    assert_eq!(
+        package.enabled_features(&selected),
+        set(&["markdown", "unstable-pre-spec"])
+    );
+    // Every feature on its own is the same set as one of the others except `markdown`.
+    assert_eq!(package.feature_matrix().len(), 4);
+}
+
+#[test]
+fn path_dependencies() {
+    let root =
+        env::temp_dir().join(format!("ruma-check-path-deps-{}", std::process::id()));
+    let write = |path: &str, text: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    };
+    write(
+        "app/Cargo.toml",
+        "[package]\nname = \"app\"\n[dependencies]\nserde = \"1\"\n\
+         ruma-macros = { workspace = true }\n\
+         [workspace]\n[workspace.dependencies]\n\
+         ruma-macros = { path = \"../ruma/ruma-macros\" }\n\
+         [patch.crates-io]\nruma = { path = \"../ruma/ruma\" }\n",
+    );
+    write(
+        "ruma/ruma/Cargo.toml",
+        "[package]\nname = \"ruma\"\nedition = \"2018\"\n\
+         [target.'cfg(unix)'.dependencies]\nruma-common = { path = \"../ruma-common\" }\n",
+    );
+    write("ruma/ruma-common/Cargo.toml", "[package]\nname = \"ruma-common\"\n");
+    write("ruma/ruma-macros/Cargo.toml", "[package]\nname = \"ruma-macros\"\n");
+
+    let info = CargoInfo::from_manifests(&root.join("app/Cargo.toml")).map(|mut info| {
+        info.find_path_deps(&root.join("app/Cargo.toml"));
+        info
+    });
+    fs::remove_dir_all(&root).unwrap();
+
+    let info = info.unwrap();
+    let names = info.checked().map(|p| p.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["app", "ruma", "ruma-macros", "ruma-common"]);
+    assert_eq!(info.members().count(), 1);
+    assert_eq!(info.packages[1].edition, Edition::Edition2018);
 }
@@ -1,61 +1,347 @@
-use std::path::Path;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use syntax::{ast::AstNode, Parse, SourceFile, SyntaxNode, SyntaxToken, WalkEvent};
+
+use crate::{
+    cfg_eval::CfgOptions,
+    error::{Diagnostic, Emitter, Label, Severity},
+    macro_exp::EXPANSION_STOPPED,
+    timings::{self, Phase},
+};
+
+/// Declare the modules of the built in rules and register their rules in
+/// `Registry::default`, a new built in rule is added with one `module::Rule` entry.
+macro_rules! rules {
+    ($($module:ident::$rule:ident),* $(,)?) => {
+        $(mod $module;)*
 
-use syntax::{ast::AstNode, SourceFile, SyntaxNode, SyntaxToken};
+        impl Default for Registry {
+            fn default() -> Self {
+                let mut registry = Self { rules: vec![], token_rules: vec![] };
+                $(registry.register::<$module::$rule>();)*
+                registry
+            }
+        }
+    };
+}
+
+rules![ban_mod::BanMod, macro_fmt::MacroFmt];
+
+/// The code of diagnostics for syntax errors found while parsing.
+pub const SYNTAX_ERROR: &str = "RC0000";
+
+/// What a rule is, implemented by `declare_rule!`.
+pub trait Rule {
+    fn name(&self) -> &'static str;
+    /// The stable code of every diagnostic this rule emits, i.e. `RC0001`.
+    fn code(&self) -> &'static str;
+    /// The severity the diagnostics of this rule are emitted at, rules push their
+    /// diagnostics with it.
+    fn severity(&self) -> Severity;
+    /// The doc comment the rule was declared with.
+    fn doc(&self) -> &'static str;
+}
 
-use crate::error::Emitter;
+/// Declare a rule, its struct, code, default severity and name, the doc comment
+/// documents the rule in reports that describe it.
+///
+/// ```ignore
+/// declare_rule! {
+///     /// Module files are banned.
+///     pub struct BanMod;
+///     code: "RC0001",
+///     severity: Error,
+///     name: "Module files (mod.rs) are banned.",
+/// }
+/// ```
+///
+/// The struct derives `Debug` and `Default`, a new one is made for each file, so a
+/// declared rule only needs a `NodeRule` impl and `Registry::register` to run. A built
+/// in rule is registered by listing it in `rules!`, its module is declared there too.
+#[macro_export]
+macro_rules! declare_rule {
+    (
+        $(#[doc = $doc:literal])*
+        $vis:vis struct $rule:ident;
+        $($meta:tt)*
+    ) => {
+        $(#[doc = $doc])*
+        #[derive(Debug, Default)]
+        $vis struct $rule;
 
-mod ban_mod;
-mod macro_fmt;
+        $crate::declare_rule!(@impl $rule [$($doc)*] $($meta)*);
+    };
+    (
+        $(#[doc = $doc:literal])*
+        $vis:vis struct $rule:ident { $($field_vis:vis $field:ident: $ty:ty),* $(,)? }
+        $($meta:tt)*
+    ) => {
+        $(#[doc = $doc])*
+        #[derive(Debug, Default)]
+        $vis struct $rule { $($field_vis $field: $ty),* }
 
-pub trait NodeRule {
-    fn name(&self) -> &str;
+        $crate::declare_rule!(@impl $rule [$($doc)*] $($meta)*);
+    };
+    (
+        @impl $rule:ident [$($doc:literal)*]
+        code: $code:literal,
+        severity: $severity:ident,
+        name: $name:literal $(,)?
+    ) => {
+        impl $crate::rules::Rule for $rule {
+            fn name(&self) -> &'static str { $name }
+            fn code(&self) -> &'static str { $code }
+            fn severity(&self) -> $crate::error::Severity {
+                $crate::error::Severity::$severity
+            }
+            fn doc(&self) -> &'static str { concat!($($doc, "\n"),*) }
+        }
+    };
+}
+
+/// A rule run on every node of a file, it collects what it finds in `apply_rule` and
+/// reports it from `validate` once the whole file was walked.
+pub trait NodeRule: Rule {
+    /// Can this rule run on a tree recovered from syntax errors, rules that look at
+    /// the exact shape of code should opt out to avoid false positives.
+    fn tolerates_errors(&self) -> bool { true }
     fn apply_rule(&mut self, node: &SyntaxNode);
     fn match_node(&self, node: &SyntaxNode) -> bool;
     fn validate(&self, path: &str, emitter: &mut Emitter) -> Result<(), crate::EzError>;
 }
 
-pub trait TokenRule {
-    fn apply_rule(&mut self, node: &SyntaxToken);
-    fn match_node(&self, node: &SyntaxToken) -> bool;
-    fn validate(&self, emitter: &mut Emitter) -> Result<(), crate::EzError>;
+/// Like `NodeRule` for tokens, registered with `Registry::register_token_rule`.
+pub trait TokenRule: Rule {
+    fn tolerates_errors(&self) -> bool { true }
+    fn apply_rule(&mut self, token: &SyntaxToken);
+    fn match_node(&self, token: &SyntaxToken) -> bool;
+    fn validate(&self, path: &str, emitter: &mut Emitter) -> Result<(), crate::EzError>;
 }
 
+/// Check a file with the built in rules.
 pub fn validate_source<P: AsRef<Path>>(
     path: &P,
     text: &str,
     emitter: &mut Emitter,
 ) -> Result<(), crate::EzError> {
-    let source =
-        SourceFile::parse(text).ok().expect("TODO: SyntaxError's from SourceFile::parse");
+    Registry::default().validate_source(path, text, emitter)
+}
+
+/// Parse a file of any edition, the parser handles edition dependent keywords like
+/// `async` and `dyn` by context.
+///
+/// The parser of the pinned rust-analyzer predates 2021, reserved prefixes like
+/// `k#ident` or `f"..."` are lexed the way 2018 lexes them. Disjoint closure captures
+/// don't change syntax, the rules don't see them either way.
+pub fn parse(text: &str) -> Parse<SourceFile> {
+    let _t = timings::phase(Phase::Parse);
+    SourceFile::parse(text)
+}
+
+/// Run the built in rules over an already parsed file, skipping code every one of
+/// `cfgs` turns off.
+pub fn validate_parsed<P: AsRef<Path>>(
+    path: &P,
+    parse: &Parse<SourceFile>,
+    cfgs: &[CfgOptions],
+    emitter: &mut Emitter,
+) -> Result<(), crate::EzError> {
+    Registry::default().validate_parsed(path, parse, cfgs, emitter)
+}
 
-    // println!("{:#?}", source.syntax());
+/// What a diagnostic code means, for output formats that describe the rules they
+/// report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleMeta {
+    pub code: &'static str,
+    pub name: String,
+    /// The severity diagnostics with this code are emitted at.
+    pub severity: Severity,
+    /// What the rule checks and why, may be empty.
+    pub doc: String,
+}
 
-    let mut rules = init_rules();
+/// The rules files are checked with, the built in rules unless more are registered.
+///
+/// Rules collect what they find while a file is walked so a fresh one is made for
+/// every file.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    rules: Vec<fn() -> Box<dyn NodeRule>>,
+    token_rules: Vec<fn() -> Box<dyn TokenRule>>,
+}
 
-    for child in source.syntax().descendants() {
-        let mut apply =
-            rules.iter_mut().filter(|r| r.match_node(&child)).collect::<Vec<_>>();
+impl Registry {
+    /// Check files with `R` as well, a new `R::default()` is made for each file.
+    pub fn register<R: NodeRule + Default + 'static>(&mut self) -> &mut Self {
+        fn make<R: NodeRule + Default + 'static>() -> Box<dyn NodeRule> {
+            Box::new(R::default())
+        }
+        self.rules.push(make::<R>);
+        self
+    }
+
+    /// Check files with the token rule `R` as well.
+    pub fn register_token_rule<R: TokenRule + Default + 'static>(&mut self) -> &mut Self {
+        fn make<R: TokenRule + Default + 'static>() -> Box<dyn TokenRule> {
+            Box::new(R::default())
+        }
+        self.token_rules.push(make::<R>);
+        self
+    }
+
+    /// The code and name of every registered rule and of the diagnostics ruma-check
+    /// emits itself.
+    pub fn meta(&self) -> Vec<RuleMeta> {
+        let token_rules = self.init_token_rules();
+        self.init()
+            .iter()
+            .map(|rule| rule_meta(&**rule))
+            .chain(token_rules.iter().map(|rule| rule_meta(&**rule)))
+            .chain(std::iter::once(RuleMeta {
+                code: SYNTAX_ERROR,
+                name: "The file could not be parsed.".to_owned(),
+                severity: Severity::Error,
+                doc: String::new(),
+            }))
+            .chain(std::iter::once(RuleMeta {
+                code: EXPANSION_STOPPED,
+                name: "Macro expansion was stopped by a limit.".to_owned(),
+                severity: Severity::Error,
+                doc: String::new(),
+            }))
+            .collect()
+    }
+
+    pub fn validate_source<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        text: &str,
+        emitter: &mut Emitter,
+    ) -> Result<(), crate::EzError> {
+        self.validate_parsed(path, &parse(text), &[CfgOptions::default()], emitter)
+    }
+
+    /// Run every rule over an already parsed file, skipping code every one of `cfgs`
+    /// turns off.
+    ///
+    /// The file is walked once for all of `cfgs`, like the sets of a feature matrix,
+    /// rules see each node any of them compiles a single time.
+    pub fn validate_parsed<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        parse: &Parse<SourceFile>,
+        cfgs: &[CfgOptions],
+        emitter: &mut Emitter,
+    ) -> Result<(), crate::EzError> {
+        let path = path.as_ref().to_str().ok_or(format!(
+            "Failed to convert path to string `{}`",
+            path.as_ref().display()
+        ))?;
+        let source = parse.tree();
+
+        if !parse.errors().is_empty() {
+            emitter.add_node_file(path, source.syntax());
+        }
+        for error in parse.errors() {
+            emitter.push(
+                Diagnostic::new(Severity::Error, SYNTAX_ERROR, &error.to_string(), path)
+                    .with_label(Label::primary(path, error.range(), "")),
+            );
+        }
+
+        let (mut rules, mut token_rules) = (self.init(), self.init_token_rules());
+        // The tree is still walked when it had errors, it is recovered as well as the
+        // parser could.
+        if !parse.errors().is_empty() {
+            rules.retain(|rule| rule.tolerates_errors());
+            token_rules.retain(|rule| rule.tolerates_errors());
+        }
+        let timed = timings::is_enabled();
+        let mut spent = vec![Duration::default(); rules.len()];
+        let mut token_spent = vec![Duration::default(); token_rules.len()];
+
+        let mut walk = source.syntax().preorder();
+        while let Some(event) = walk.next() {
+            let child = match event {
+                WalkEvent::Enter(child) => child,
+                WalkEvent::Leave(_) => continue,
+            };
+            if !cfgs.iter().any(|cfg| cfg.is_enabled(&child)) {
+                walk.skip_subtree();
+                continue;
+            }
+            for (rule, spent) in rules.iter_mut().zip(&mut spent) {
+                time(timed, spent, || {
+                    if rule.match_node(&child) {
+                        rule.apply_rule(&child);
+                    }
+                });
+            }
+            // Every token is the child of exactly one node.
+            if !token_rules.is_empty() {
+                for token in child.children_with_tokens().filter_map(|it| it.into_token())
+                {
+                    for (rule, spent) in token_rules.iter_mut().zip(&mut token_spent) {
+                        time(timed, spent, || {
+                            if rule.match_node(&token) {
+                                rule.apply_rule(&token);
+                            }
+                        });
+                    }
+                }
+            }
+        }
 
-        for rule in &mut apply {
-            rule.apply_rule(&child);
+        for (rule, spent) in rules.iter_mut().zip(spent) {
+            let start = Instant::now();
+            rule.validate(path, emitter)?;
+            timings::rule(rule.name(), spent + start.elapsed());
         }
+        for (rule, spent) in token_rules.iter_mut().zip(token_spent) {
+            let start = Instant::now();
+            rule.validate(path, emitter)?;
+            timings::rule(rule.name(), spent + start.elapsed());
+        }
+
+        Ok(())
     }
 
-    for rule in &mut rules {
-        rule.validate(
-            path.as_ref().to_str().ok_or(format!(
-                "Failed to convert path to string `{}`",
-                path.as_ref().display()
-            ))?,
-            emitter,
-        )?;
+    fn init(&self) -> Vec<Box<dyn NodeRule>> {
+        self.rules.iter().map(|rule| rule()).collect()
     }
 
-    Ok(())
+    fn init_token_rules(&self) -> Vec<Box<dyn TokenRule>> {
+        self.token_rules.iter().map(|rule| rule()).collect()
+    }
 }
 
-fn init_rules() -> Vec<Box<dyn NodeRule>> {
-    vec![ban_mod::BanMod::new(), macro_fmt::MacroFmt::new()]
+/// What reports say about `rule`.
+fn rule_meta<R: Rule + ?Sized>(rule: &R) -> RuleMeta {
+    RuleMeta {
+        code: rule.code(),
+        name: rule.name().to_owned(),
+        severity: rule.severity(),
+        // Doc comments keep the space after `///`.
+        doc: rule
+            .doc()
+            .lines()
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Run `f`, adding the time it took to `spent` when `timed`.
+fn time(timed: bool, spent: &mut Duration, f: impl FnOnce()) {
+    let start = if timed { Some(Instant::now()) } else { None };
+    f();
+    if let Some(start) = start {
+        *spent += start.elapsed();
+    }
 }
 
 #[test]
@@ -91,3 +377,126 @@ fn main() {
         .unwrap();
     emitter.emit().unwrap();
 }
+
+#[test]
+fn syntax_errors() {
+    let mut emitter = Emitter::default();
+    let text = r#"fn main() {
+    let a = ;
+    foo!(a);
+}
+"#;
+    validate_source(&std::path::PathBuf::from("src/mod.rs"), text, &mut emitter).unwrap();
+
+    let codes = emitter.diagnostics().iter().map(|d| d.code.as_str()).collect::<Vec<_>>();
+    // The file still gets checked by rules that don't mind a broken tree.
+    assert!(codes.contains(&SYNTAX_ERROR));
+    assert!(codes.contains(&"RC0001"));
+    assert!(!codes.contains(&"RC0002"));
+}
+
+#[test]
+fn cfg_disabled_code() {
+    let text = r#"#[cfg(feature = "unstable")]
+fn a() {
+    foo!(a);
+}
+"#;
+    let path = std::path::PathBuf::from("src/lib.rs");
+    let cfg = CfgOptions::default().with_features(vec![]);
+    let mut emitter = Emitter::default();
+    validate_parsed(&path, &parse(text), &[cfg.clone()], &mut emitter).unwrap();
+    assert!(emitter.diagnostics().is_empty());
+
+    // Code one set of a matrix compiles is checked.
+    let unstable = CfgOptions::default().with_features(vec!["unstable".to_owned()]);
+    validate_parsed(&path, &parse(text), &[cfg, unstable], &mut emitter).unwrap();
+    assert_eq!(emitter.diagnostics().len(), 1);
+}
+
+#[test]
+fn registered_rules() {
+    declare_rule! {
+        /// Unwrapping panics, return an error instead.
+        ///
+        /// Tests may unwrap.
+        struct NoUnwrap { found: Vec<SyntaxNode> }
+        code: "XX0001",
+        severity: Warning,
+        name: "No unwrap.",
+    }
+
+    impl NodeRule for NoUnwrap {
+        fn apply_rule(&mut self, node: &SyntaxNode) { self.found.push(node.clone()); }
+        fn match_node(&self, node: &SyntaxNode) -> bool {
+            node.kind() == syntax::SyntaxKind::METHOD_CALL_EXPR
+                && node.text().to_string().ends_with("unwrap()")
+        }
+        fn validate(
+            &self,
+            path: &str,
+            emitter: &mut Emitter,
+        ) -> Result<(), crate::EzError> {
+            for node in &self.found {
+                emitter.push(
+                    Diagnostic::new(self.severity(), self.code(), self.name(), path)
+                        .with_label(Label::primary(path, node.text_range(), "")),
+                );
+            }
+            Ok(())
+        }
+    }
+
+    declare_rule! {
+        /// Track work in issues.
+        struct NoTodo { found: Vec<SyntaxToken> }
+        code: "XX0002",
+        severity: Note,
+        name: "No TODO comments.",
+    }
+
+    impl TokenRule for NoTodo {
+        fn apply_rule(&mut self, token: &SyntaxToken) { self.found.push(token.clone()); }
+        fn match_node(&self, token: &SyntaxToken) -> bool {
+            token.kind() == syntax::SyntaxKind::COMMENT && token.text().contains("TODO")
+        }
+        fn validate(
+            &self,
+            path: &str,
+            emitter: &mut Emitter,
+        ) -> Result<(), crate::EzError> {
+            for token in &self.found {
+                emitter.push(
+                    Diagnostic::new(self.severity(), self.code(), self.name(), path)
+                        .with_label(Label::primary(path, token.text_range(), "")),
+                );
+            }
+            Ok(())
+        }
+    }
+
+    let mut registry = Registry::default();
+    registry.register::<NoUnwrap>().register_token_rule::<NoTodo>();
+    assert!(registry.meta().iter().any(|rule| rule.code == "XX0002"));
+    let meta = registry.meta().into_iter().find(|rule| rule.code == "XX0001").unwrap();
+    assert_eq!(meta.severity, Severity::Warning);
+    assert_eq!(
+        meta.doc,
+        "Unwrapping panics, return an error instead.\n\nTests may unwrap."
+    );
+
+    let text = "fn main() {\n    // TODO\n    a.unwrap();\n}\n";
+    let mut emitter = Emitter::default();
+    registry
+        .validate_source(&std::path::PathBuf::from("src/lib.rs"), text, &mut emitter)
+        .unwrap();
+    registry
+        .validate_source(&std::path::PathBuf::from("src/main.rs"), text, &mut emitter)
+        .unwrap();
+    // A fresh rule for every file, reporting at the declared severity.
+    let severities = emitter.diagnostics().iter().map(|d| d.severity).collect::<Vec<_>>();
+    assert_eq!(
+        severities,
+        vec![Severity::Warning, Severity::Note, Severity::Warning, Severity::Note]
+    );
+}
@@ -2,16 +2,22 @@ use std::path::PathBuf;
 
 use syntax::SyntaxNode;
 
-use crate::{error::Emitter, rules::NodeRule};
-
-#[derive(Debug, Default)]
-pub struct BanMod;
-
-impl BanMod {
-    pub fn new() -> Box<Self> { Box::new(Self::default()) }
+use crate::{
+    declare_rule,
+    error::{Diagnostic, Emitter, Suggestion},
+    rules::{NodeRule, Rule},
+};
+
+declare_rule! {
+    /// Module files (`mod.rs`) are banned, the module `foo` lives in `foo.rs` next to the
+    /// `foo/` folder of its submodules.
+    pub struct BanMod;
+    code: "RC0001",
+    severity: Error,
+    name: "Module files (mod.rs) are banned.",
 }
+
 impl NodeRule for BanMod {
-    fn name(&self) -> &str { "Module files (mod.rs) are banned." }
     fn apply_rule(&mut self, _: &SyntaxNode) {}
 
     fn match_node(&self, _: &SyntaxNode) -> bool { false }
@@ -25,15 +31,14 @@ impl NodeRule for BanMod {
             p.set_extension("rs");
             let file = p;
 
-            emitter.simple_sugg(
-                self.name(),
-                &format!(
-                    "create a `{}` file and `{}` folder and remove `{}`",
-                    file.display(),
-                    folder.display(),
-                    path
-                ),
-                path,
+            emitter.push(
+                Diagnostic::new(self.severity(), self.code(), self.name(), path)
+                    .with_suggestion(Suggestion::text(&format!(
+                        "create a `{}` file and `{}` folder and remove `{}`",
+                        file.display(),
+                        folder.display(),
+                        path
+                    ))),
             );
         }
         Ok(())
@@ -7,33 +7,41 @@ use syntax::{
     Direction, SourceFile, SyntaxKind, SyntaxNode, SyntaxToken, TextRange, WalkEvent, T,
 };
 
-use crate::{error::Emitter, rules::NodeRule};
+use crate::{
+    declare_rule,
+    error::{Diagnostic, Emitter, Label},
+    rules::{NodeRule, Rule},
+};
 
 type NodeOrToken = syntax::NodeOrToken<SyntaxNode, SyntaxToken>;
 
 const LINE_LEN: usize = 80;
 const INDENT: usize = 4;
 
-#[derive(Debug, Default)]
-pub struct MacroFmt {
-    found: Vec<SyntaxNode>,
-}
-
-impl MacroFmt {
-    pub fn new() -> Box<Self> { Box::new(Self::default()) }
+declare_rule! {
+    /// Macro calls are formatted the way rustfmt formats function calls, arguments that
+    /// don't fit in the line go one per line.
+    pub struct MacroFmt {
+        found: Vec<SyntaxNode>,
+    }
+    code: "RC0002",
+    severity: Error,
+    name: "Correct formatting of macro calls.",
 }
 
 impl NodeRule for MacroFmt {
-    fn name(&self) -> &str { "Correct formatting of macro calls." }
+    fn tolerates_errors(&self) -> bool { false }
     fn apply_rule(&mut self, node: &SyntaxNode) {
         if let Some(mac) = ast::MacroCall::cast(node.clone()) {
-            let indent = walk_ancestors_until(mac.syntax(), |kind| {
-                println!("{:?}", kind);
-                kind == SyntaxKind::WHITESPACE
-            })
-            .map_or(0, |ws| {
-                ws.to_string().replace("\t", "    ").chars().filter(|c| *c == ' ').count()
-            });
+            let indent =
+                walk_ancestors_until(mac.syntax(), |kind| kind == SyntaxKind::WHITESPACE)
+                    .map_or(0, |ws| {
+                        ws.to_string()
+                            .replace("\t", "    ")
+                            .chars()
+                            .filter(|c| *c == ' ')
+                            .count()
+                    });
 
             if needs_formatting(&mac, indent) {
                 self.found.push(mac.syntax().clone());
@@ -47,12 +55,10 @@ impl NodeRule for MacroFmt {
 
     fn validate(&self, path: &str, emitter: &mut Emitter) -> Result<(), crate::EzError> {
         for mac in &self.found {
-            emitter.sugg_with_span(
-                "Hello message",
-                "",
-                mac.text_range(),
-                mac.clone(),
-                path,
+            emitter.add_node_file(path, mac);
+            emitter.push(
+                Diagnostic::new(self.severity(), self.code(), "Hello message", path)
+                    .with_label(Label::primary(path, mac.text_range(), "")),
             );
         }
 
@@ -91,8 +97,6 @@ fn needs_formatting(mac: &ast::MacroCall, indent: usize) -> bool {
             )
         };
 
-        println!("{} {} {:?} {:?}", current_line_len, indent_level, token, next);
-
         let text = token.text();
         if text.contains('\n') {
             if current_line_len > LINE_LEN
@@ -126,7 +130,6 @@ fn needs_formatting(mac: &ast::MacroCall, indent: usize) -> bool {
         last = Some(token.kind());
     }
 
-    println!("FALSE");
     true
 }
 
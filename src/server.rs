@@ -0,0 +1,362 @@
+//! A long running `ruma-check server` that answers check requests over a Unix socket.
+//!
+//! Loading `cargo metadata` dominates small runs, the server keeps each workspace it
+//! has seen loaded along with every file it has parsed so a request only re-parses the
+//! files that changed. With `--expand` the macro expansion database of the workspace is
+//! kept as well, only what depends on a changed file is expanded again.
+//!
+//! A request is the working directory of the client followed by its arguments, one per
+//! line, ending with an empty line. The response is the exit code on the first line
+//! followed by everything the run would have printed.
+
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io::{BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    changes, check_workspace,
+    cli::{Args, Command},
+    configure_emitter,
+    error::{Buffer, ColorChoice, Emitter, MessageFormat},
+    macro_exp,
+    project::{self, CargoInfo, Features, PROJECT_JSON},
+    timings, write_reports, EzError, Parses,
+};
+
+/// How long a client may take to send its request or read the response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the server listens when no `--socket` is given, in the user's runtime directory
+/// so other users can't connect.
+pub fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("ruma-check.sock"),
+        None => {
+            let user = env::var("USER").unwrap_or_else(|_| "default".to_owned());
+            env::temp_dir().join(format!("ruma-check-{}", user)).join("ruma-check.sock")
+        }
+    }
+}
+
+/// Remove the arguments that only mean something to the client.
+pub fn forwarded_args(args: &[String]) -> Vec<String> {
+    let mut forwarded = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--client" => {}
+            "--socket" => {
+                iter.next();
+            }
+            arg if arg.starts_with("--socket=") => {}
+            _ => forwarded.push(arg.clone()),
+        }
+    }
+    forwarded
+}
+
+/// Send a request to a running server, print its output and return the exit code.
+///
+/// The output goes to stdout when `stdout` is set, stderr otherwise.
+pub fn forward(
+    socket: &Path,
+    cwd: &Path,
+    args: &[String],
+    stdout: bool,
+) -> Result<i32, EzError> {
+    check_private(socket_dir(socket))?;
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| format!("Failed to connect to `{}`: {}", socket.display(), e))?;
+
+    let mut request = format!("{}\n", cwd.display());
+    for arg in args {
+        request.push_str(arg);
+        request.push('\n');
+    }
+    request.push('\n');
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (code, output) = response.split_at(response.find('\n').unwrap_or(response.len()));
+    let output = output.trim_start_matches('\n');
+    if stdout {
+        print!("{}", output);
+    } else {
+        eprint!("{}", output);
+    }
+
+    Ok(code.parse().map_err(|_| format!("Malformed response from server: `{}`", code))?)
+}
+
+/// Listen on `socket` answering requests one at a time, this only returns on error.
+///
+/// Only the user running the server can connect, the socket is created with mode 0600
+/// in a directory only they can use, see `check_private`.
+pub fn serve(socket: &Path) -> Result<(), EzError> {
+    let dir = socket_dir(socket);
+    // Only created when missing, an existing directory keeps its permissions.
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    check_private(dir)?;
+    remove_stale(socket)?;
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| format!("Failed to listen on `{}`: {}", socket.display(), e))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    eprintln!("ruma-check server listening on {}", socket.display());
+
+    let mut state = State::default();
+    for stream in listener.incoming() {
+        let handled = stream.map_err(EzError::from).and_then(|s| {
+            // A client that stalls would block everyone behind it.
+            s.set_read_timeout(Some(TIMEOUT))?;
+            s.set_write_timeout(Some(TIMEOUT))?;
+            state.handle(s)
+        });
+        if let Err(e) = handled {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+fn socket_dir(socket: &Path) -> &Path {
+    socket.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+/// Refuse a socket directory other users could swap the socket in, it has to be a real
+/// directory owned by us with mode 0700.
+fn check_private(dir: &Path) -> Result<(), EzError> {
+    let meta = fs::symlink_metadata(dir)
+        .map_err(|e| format!("Failed to read `{}`: {}", dir.display(), e))?;
+    // Safety: `getuid` can't fail and touches no memory.
+    let uid = unsafe { libc::getuid() };
+    let problem = if !meta.is_dir() {
+        "is not a directory".to_owned()
+    } else if meta.uid() != uid {
+        "is not owned by the current user".to_owned()
+    } else if meta.mode() & 0o777 != 0o700 {
+        format!("has mode {:o} instead of 700", meta.mode() & 0o777)
+    } else {
+        return Ok(());
+    };
+    Err(format!("The socket directory `{}` {}", dir.display(), problem).into())
+}
+
+/// Remove a socket left behind by a server that was killed, it stops us from binding.
+///
+/// Anything that isn't a socket or still has a server listening is left alone.
+fn remove_stale(socket: &Path) -> Result<(), EzError> {
+    let meta = match fs::symlink_metadata(socket) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(format!("`{}` exists and is not a socket", socket.display()).into());
+    }
+    if UnixStream::connect(socket).is_ok() {
+        return Err(
+            format!("A server is already listening on `{}`", socket.display()).into()
+        );
+    }
+    fs::remove_file(socket)?;
+    Ok(())
+}
+
+/// A loaded workspace and when each of its manifests was last modified.
+struct Workspace {
+    info: CargoInfo,
+    /// Loaded by the first `--expand` request.
+    expander: Option<macro_exp::Workspace>,
+    manifests: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Workspace {
+    fn load(root: &Path, args: &Args) -> Result<Self, EzError> {
+        let info = CargoInfo::build_crate_root(root, args)?;
+
+        let mut manifests = vec![
+            root.join("Cargo.toml"),
+            root.join("Cargo.lock"),
+            root.join(PROJECT_JSON),
+        ];
+        manifests.extend(info.packages.iter().map(|p| p.manifest.clone()));
+        let manifests =
+            manifests.into_iter().map(|m| (m.clone(), modified(&m))).collect();
+
+        Ok(Self { info, expander: None, manifests })
+    }
+
+    fn is_stale(&self) -> bool {
+        self.manifests.iter().any(|(path, time)| modified(path) != *time)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// A workspace directory and every flag that changes how it is loaded, requests with
+/// different flags don't share a `Workspace`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct WorkspaceKey {
+    dir: PathBuf,
+    features: Features,
+    target: Option<String>,
+    offline: bool,
+    frozen: bool,
+    path_deps: bool,
+}
+
+impl WorkspaceKey {
+    fn new(dir: &Path, args: &Args) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            features: args.features.clone(),
+            target: args.target.clone(),
+            offline: args.offline,
+            frozen: args.frozen,
+            path_deps: args.path_deps,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    workspaces: FxHashMap<WorkspaceKey, Workspace>,
+    files: Parses,
+}
+
+impl State {
+    fn handle(&mut self, mut stream: UnixStream) -> Result<(), EzError> {
+        let mut lines = vec![];
+        for line in BufReader::new(&stream).lines() {
+            let line = line?;
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+        let (cwd, args) = lines.split_first().ok_or("Empty request")?;
+
+        let (code, output) = match Args::parse(args) {
+            Ok(args) => self
+                .check(Path::new(cwd), &args)
+                .unwrap_or_else(|e| (1, e.to_string() + "\n")),
+            Err(e) => (2, e + "\n"),
+        };
+        stream.write_all(format!("{}\n{}", code, output).as_bytes())?;
+        Ok(())
+    }
+
+    fn check(&mut self, cwd: &Path, args: &Args) -> Result<(i32, String), EzError> {
+        if args.command != Command::Check {
+            return Err("The server only answers `check` requests".into());
+        }
+        // Don't let a failed timed request leave timing on for the next one.
+        if args.timings {
+            timings::enable();
+        } else {
+            timings::disable();
+        }
+
+        let key = WorkspaceKey::new(cwd, args);
+        if self.workspaces.get(&key).map_or(true, Workspace::is_stale) {
+            let workspace = Workspace::load(cwd, args)?;
+            self.workspaces.insert(key.clone(), workspace);
+        }
+        let workspace = self.workspaces.get_mut(&key).expect("loaded above");
+        if args.expand && workspace.expander.is_none() {
+            workspace.expander = Some(project::load_expander(cwd, args)?);
+        }
+        let expander = workspace.expander.as_mut().filter(|_| args.expand);
+
+        // Auto would check the server's own stderr, the client asks for color.
+        let color = args.message_format == MessageFormat::Human
+            && args.color == ColorChoice::Always;
+        let out = Buffer::default();
+        let mut emitter = Emitter::default();
+        configure_emitter(cwd, args, &mut emitter, out.clone(), color)?;
+        let changes = changes(cwd, args)?;
+        let crates = check_workspace(
+            &workspace.info,
+            args,
+            changes.as_ref(),
+            Some(&mut self.files),
+            expander,
+            &mut emitter,
+        )?;
+        write_reports(cwd, args, &emitter, &crates)?;
+
+        let code = emitter.exit_code();
+        emitter.emit()?;
+        let mut output = out.contents();
+        // Timings would break up machine readable output, only send them with human
+        // output.
+        if args.message_format == MessageFormat::Human {
+            output.push_str(&timings::report().unwrap_or_default());
+        }
+        timings::disable();
+
+        Ok((code, output))
+    }
+}
+
+#[test]
+fn only_stale_sockets_are_removed() {
+    let dir = env::temp_dir().join(format!("ruma-check-sockets-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let file = dir.join("file.sock");
+    fs::write(&file, "not a socket").unwrap();
+    assert!(remove_stale(&file).is_err());
+    assert!(file.exists());
+
+    let socket = dir.join("live.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    assert!(remove_stale(&socket).is_err());
+    drop(listener);
+    // Nothing accepts connections any more.
+    remove_stale(&socket).unwrap();
+    assert!(!socket.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn socket_dirs_are_private() {
+    let dir = env::temp_dir().join(format!("ruma-check-private-{}", std::process::id()));
+    let private = dir.join("private");
+    DirBuilder::new().recursive(true).mode(0o700).create(&private).unwrap();
+    check_private(&private).unwrap();
+
+    let link = dir.join("link");
+    std::os::unix::fs::symlink(&private, &link).unwrap();
+    assert!(check_private(&link).is_err());
+
+    fs::set_permissions(&private, fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(check_private(&private).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn client_args_are_not_forwarded() {
+    let args = ["--client", "--socket", "/tmp/s.sock", "-p", "ruma"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    assert_eq!(forwarded_args(&args), vec!["-p".to_owned(), "ruma".to_owned()]);
+}
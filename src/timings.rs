@@ -0,0 +1,179 @@
+//! Opt-in profiling of a run, enabled with `--timings`.
+//!
+//! Timing is collected into a thread local so the phases being measured do not have
+//! to thread any state around, when disabled every guard is a no-op.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How many of the slowest files and hottest queries are reported.
+const TOP: usize = 10;
+
+/// The coarse steps a run goes through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Running `cargo metadata` and loading the workspace.
+    Metadata,
+    /// Building the salsa database and crate graph.
+    CrateGraph,
+    /// Parsing source files.
+    Parse,
+    /// Expanding macro calls.
+    Expansion,
+    /// Salsa queries executed by the semantic layer, approximate. The queries run while
+    /// expanding so this is part of the `Expansion` time, not in addition to it.
+    Semantic,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Metadata => "metadata",
+            Phase::CrateGraph => "crate graph",
+            Phase::Parse => "parse",
+            Phase::Expansion => "expansion",
+            Phase::Semantic => "semantic queries (approximate, part of expansion)",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+    phases: BTreeMap<Phase, Duration>,
+    rules: BTreeMap<String, Duration>,
+    files: Vec<(PathBuf, Duration)>,
+    /// Execution count and approximate time spent in each salsa query.
+    queries: BTreeMap<String, (usize, Duration)>,
+}
+
+thread_local! {
+    static TIMINGS: RefCell<Option<Timings>> = RefCell::new(None);
+}
+
+/// Start collecting timings on this thread.
+pub fn enable() { TIMINGS.with(|t| *t.borrow_mut() = Some(Timings::default())); }
+
+/// Stop collecting timings and throw away what was collected.
+pub fn disable() { TIMINGS.with(|t| *t.borrow_mut() = None); }
+
+pub fn is_enabled() -> bool { TIMINGS.with(|t| t.borrow().is_some()) }
+
+fn with(f: impl FnOnce(&mut Timings)) {
+    TIMINGS.with(|t| {
+        if let Some(timings) = &mut *t.borrow_mut() {
+            f(timings)
+        }
+    })
+}
+
+#[derive(Debug)]
+enum Kind {
+    Phase(Phase),
+    File(PathBuf),
+}
+
+/// Records the time between its creation and when it is dropped.
+#[derive(Debug)]
+#[must_use = "the time is recorded when the guard is dropped"]
+pub struct Guard {
+    kind: Option<Kind>,
+    start: Instant,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let spent = self.start.elapsed();
+        match self.kind.take() {
+            Some(Kind::Phase(phase)) => {
+                with(|t| *t.phases.entry(phase).or_default() += spent)
+            }
+            Some(Kind::File(path)) => with(|t| t.files.push((path, spent))),
+            None => {}
+        }
+    }
+}
+
+fn guard(kind: impl FnOnce() -> Kind) -> Guard {
+    Guard { kind: if is_enabled() { Some(kind()) } else { None }, start: Instant::now() }
+}
+
+/// Time a phase until the returned guard is dropped.
+pub fn phase(phase: Phase) -> Guard { guard(|| Kind::Phase(phase)) }
+
+/// Time checking a single file until the returned guard is dropped.
+pub fn file(path: &Path) -> Guard { guard(|| Kind::File(path.to_owned())) }
+
+/// Add time spent matching, applying and validating a rule.
+pub fn rule(name: &str, spent: Duration) {
+    with(|t| *t.rules.entry(name.to_owned()).or_default() += spent);
+}
+
+/// Add salsa query executions, each query name paired with the time it took.
+pub fn queries(executed: impl IntoIterator<Item = (String, Duration)>) {
+    with(|t| {
+        for (name, spent) in executed {
+            let entry = t.queries.entry(name).or_default();
+            entry.0 += 1;
+            entry.1 += spent;
+            *t.phases.entry(Phase::Semantic).or_default() += spent;
+        }
+    })
+}
+
+/// Render everything collected so far, `None` if timings are not enabled.
+pub fn report() -> Option<String> { TIMINGS.with(|t| t.borrow().as_ref().map(render)) }
+
+fn render(timings: &Timings) -> String {
+    let mut buffer = String::from("timings:\n  phases:\n");
+    for (phase, spent) in &timings.phases {
+        writeln!(buffer, "    {:<48} {:>10.3?}", phase.name(), spent).unwrap();
+    }
+
+    buffer.push_str("  rules:\n");
+    let mut rules = timings.rules.iter().collect::<Vec<_>>();
+    rules.sort_by(|a, b| b.1.cmp(a.1));
+    for (rule, spent) in rules {
+        writeln!(buffer, "    {:<48} {:>10.3?}", rule, spent).unwrap();
+    }
+
+    buffer.push_str("  slowest files:\n");
+    let mut files = timings.files.iter().collect::<Vec<_>>();
+    files.sort_by(|a, b| b.1.cmp(&a.1));
+    for (file, spent) in files.into_iter().take(TOP) {
+        writeln!(buffer, "    {:<48} {:>10.3?}", file.display(), spent).unwrap();
+    }
+
+    buffer.push_str("  hottest queries (approximate):\n");
+    let mut queries = timings.queries.iter().collect::<Vec<_>>();
+    queries.sort_by(|a, b| (b.1).1.cmp(&(a.1).1));
+    for (query, (count, spent)) in queries.into_iter().take(TOP) {
+        writeln!(buffer, "    {:<36} {:>6}x {:>10.3?}", query, count, spent).unwrap();
+    }
+
+    buffer
+}
+
+#[test]
+fn disabled_records_nothing() {
+    {
+        let _t = phase(Phase::Parse);
+    }
+    assert!(report().is_none());
+
+    enable();
+    {
+        let _t = phase(Phase::Parse);
+    }
+    rule("ban-mod", Duration::from_millis(2));
+    queries(vec![("parse".to_owned(), Duration::from_millis(1))]);
+
+    let report = report().unwrap();
+    assert!(report.contains("parse"));
+    assert!(report.contains("ban-mod"));
+    assert!(report.contains("semantic queries"));
+}